@@ -1,6 +1,15 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    hash::Hash,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard,
+    },
+};
 
-use chrono::Utc;
+use chrono::{Duration, Utc};
 use dashmap::{DashMap, DashSet};
 use f_chat_rs::{
     cache::{PartialChannelData, PartialUserData},
@@ -10,29 +19,522 @@ use f_chat_rs::{
     },
     util::Timestamp,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::{
+    data::{self, CharacterDataInner},
+    dice::{RollHistory, RollStats},
+    logs::LogEntry,
+};
+
+// How many title/description/mode changes are remembered per channel.
+const CHANNEL_HISTORY_LIMIT: usize = 100;
+// How many character changes are kept for building deltas.
+// If the frontend is further behind than this, it has to take a fresh snapshot.
+const CHARACTER_LOG_LIMIT: usize = 8192;
+// The server answers a change within a moment or not at all (a refusal comes back as an error,
+// which doesn't say what it refused). Past this, the next change is someone else's.
+const PENDING_CHANGE_SECS: i64 = 5;
+
 #[derive(Debug, Default)]
 pub struct Cache {
+    interner: Interner,
     channels: DashMap<Channel, CacheChannelData>,
-    characters: DashMap<Character, CacheCharacterData>,
-    messages: DashMap<MessageChannel, Vec<Message>>,
-    ads: DashMap<Channel, Vec<Message>>,
-    bookmarks: DashSet<Character>,
-    friends: DashSet<FriendRelation>,
-    admins: DashSet<Character>,
-    global_channels: DashMap<Channel, u32>,
-    unofficial_channels: DashMap<Channel, u32>,
+    characters: DashMap<CharacterId, CacheCharacterData>,
+    messages: DashMap<MessageChannel, MessageBuffer>,
+    ads: DashMap<Channel, MessageBuffer>,
+    // Roughly what messages and ads take up, between them.
+    message_bytes: AtomicUsize,
+    retention: RwLock<Retention>,
+    rolls: RollHistory,
+    // Messages pushed out of memory, waiting to be written to the logs.
+    evicted: Mutex<Vec<(MessageChannel, LogEntry)>>,
+    bookmarks: DashSet<CharacterId>,
+    // (own character, friend). The list is short, so "is this a friend" just looks through it.
+    friends: DashSet<(CharacterId, CharacterId)>,
+    admins: DashSet<CharacterId>,
+    // The server's room lists with their head counts, each swapped out whole when it's resent.
+    global_channels: RwLock<Arc<HashMap<Channel, u32>>>,
+    unofficial_channels: RwLock<Arc<HashMap<Channel, u32>>>,
+    channel_list_changes: Mutex<Vec<ChannelListChange>>,
+    unread: DashMap<MessageChannel, UnreadData>,
+    character_log: Mutex<CharacterLog>,
+    last_seen: DashMap<CharacterId, Timestamp>,
+    presence: Mutex<Vec<PresenceChange>>,
+    // The account's own characters. The server sends our own NLN after the initial LIS,
+    // so the first of them coming online means the login flood is over.
+    own: DashSet<CharacterId>,
+    listed: AtomicBool,
+    // Keep friends and bookmarks around (as offline) when they log off, instead of forgetting them.
+    retain_offline: AtomicBool,
+    member_changes: Mutex<Vec<MemberChange>>,
+    list_changes: Mutex<Vec<ListChange>>,
+    channel_history: DashMap<Channel, VecDeque<ChannelChange>>,
+    channel_changes: Mutex<Vec<ChannelChange>>,
+    // Who we expect the next change to a channel to be from, when we're the ones making it, and since when.
+    pending_channel_changes: DashMap<Channel, (Character, Timestamp)>,
+}
+
+// A character name, as the cache holds it. Everything in here refers to characters by id,
+// so the name itself is stored once no matter how many channels, lists and messages it turns up in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CharacterId(u32);
+
+// Ids are never freed or reused. A name costs a few dozen bytes once, which is less than
+// keeping track of everywhere that still refers to it.
+//
+// Intern (or look up) before taking a guard on any of the cache's maps, and never while holding
+// `names`. Only intern writes to `names`, so readers can then hold it for as long as they like.
+#[derive(Debug, Default)]
+struct Interner {
+    ids: DashMap<Character, CharacterId>,
+    names: RwLock<Vec<Character>>,
+}
+
+impl Interner {
+    fn intern(&self, character: &Character) -> CharacterId {
+        if let Some(id) = self.ids.get(character) {
+            return *id;
+        }
+        // The entry holds the shard, so two threads can't hand out two ids for one name.
+        *self.ids.entry(*character).or_insert_with(|| {
+            let mut names = self.names.write().unwrap();
+            names.push(*character);
+            CharacterId((names.len() - 1) as u32)
+        })
+    }
+
+    // Without interning; a name we've never seen can't be in any of the maps.
+    fn id(&self, character: &Character) -> Option<CharacterId> {
+        self.ids.get(character).map(|v| *v)
+    }
+
+    fn name(&self, id: CharacterId) -> Character {
+        self.names()[id.0 as usize]
+    }
+
+    // For resolving a lot of ids at once without taking the lock for each.
+    fn names(&self) -> RwLockReadGuard<'_, Vec<Character>> {
+        self.names.read().unwrap()
+    }
+
+    fn len(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+// A message as the cache holds it, with the sender interned.
+#[derive(Debug, Clone, PartialEq)]
+struct CachedMessage {
+    timestamp: Timestamp,
+    character: CharacterId,
+    content: MessageContent,
+    context: MessageContext,
+}
+
+// What was known about where a message was said and who said it, at the time.
+// Rooms get retitled and people change their gender, and old messages should still read as they did.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MessageContext {
+    // None in PMs, or if we hadn't been told the title yet.
+    // Shared between consecutive messages with the same title, so it's only held once per change.
+    pub title: Option<Arc<str>>,
+    pub gender: Option<Gender>,
+}
+
+// A message with what it was sent under and what that is now, for telling the two apart.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageRecord {
+    #[serde(flatten)]
+    pub message: Message,
+    pub sent_as: MessageContext,
+    pub current: MessageContext,
+}
+
+impl CachedMessage {
+    fn resolve(self, names: &[Character]) -> Message {
+        Message {
+            timestamp: self.timestamp,
+            character: names[self.character.0 as usize],
+            content: self.content,
+        }
+    }
+}
+
+// How much history is held in memory before the oldest goes.
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub per_channel: usize,
+    pub budget: usize,
+    // Keep what's pushed out in the logs, rather than dropping it. Ads are always dropped.
+    pub log_evicted: bool,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            per_channel: 1000,
+            budget: 64 * 1024 * 1024,
+            log_evicted: true,
+        }
+    }
+}
+
+// One conversation's messages, oldest first, with a running estimate of the memory they hold.
+#[derive(Debug, Default)]
+pub struct MessageBuffer {
+    messages: VecDeque<CachedMessage>,
+    bytes: usize,
+}
+
+impl MessageBuffer {
+    fn push(&mut self, message: CachedMessage) -> usize {
+        let size = message_size(&message);
+        self.bytes += size;
+        self.messages.push_back(message);
+        size
+    }
+
+    fn last(&self) -> Option<&CachedMessage> {
+        self.messages.back()
+    }
+
+    fn iter(&self) -> impl DoubleEndedIterator<Item = &CachedMessage> + ExactSizeIterator {
+        self.messages.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // Takes the oldest `count` messages out, returning them and the bytes freed.
+    fn evict(&mut self, count: usize) -> (Vec<CachedMessage>, usize) {
+        let evicted: Vec<CachedMessage> = self.messages.drain(..count.min(self.len())).collect();
+        let freed = evicted.iter().map(message_size).sum();
+        self.bytes -= freed;
+        (evicted, freed)
+    }
+}
+
+// Close enough to tell which maps are big; heap held inside names isn't counted.
+fn message_size(message: &CachedMessage) -> usize {
+    size_of::<CachedMessage>()
+        + match &message.content {
+            MessageContent::Message(text) | MessageContent::Emote(text) => text.len(),
+            MessageContent::Roll(dice, results, _) => {
+                dice.iter().map(String::len).sum::<usize>() + results.len() * size_of::<i32>()
+            }
+            MessageContent::Bottle(_) => 0,
+        }
+}
+
+// Evicting a tenth at a time means the logs are written in batches, not a message at a time.
+fn eviction_count(len: usize, limit: usize) -> usize {
+    if len > limit {
+        len - limit + limit / 10
+    } else {
+        0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct MapStats {
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct CharacterLog {
+    version: u64,
+    changes: VecDeque<(u64, CharacterId, CharacterChange)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CharacterChange {
+    Added,
+    Changed,
+    Removed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceKind {
+    Online,
+    Offline,
+    Status,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PresenceChange {
+    pub character: Character,
+    pub kind: PresenceKind,
+    pub status: Status,
+    pub status_message: String,
+    pub at: Timestamp,
+    #[serde(skip)]
+    pub channels: Vec<Channel>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "field", rename_all = "snake_case")]
+pub enum ChannelChangeKind {
+    Title { old: String, new: String },
+    Description { old: String, new: String },
+    Mode { old: ChannelMode, new: ChannelMode },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelChange {
+    pub channel: Channel,
+    pub at: Timestamp,
+    // The server doesn't say who changed what, so this is only known for our own changes.
+    pub by: Option<Character>,
+    #[serde(flatten)]
+    pub change: ChannelChangeKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct MemberChange {
+    pub channel: Channel,
+    pub character: Character,
+    pub joined: bool,
+}
+
+// Who came and went from one of the lists the server keeps for us.
+// Friends are by the other character; which own character they're a friend of is in the full list.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "list", rename_all = "snake_case")]
+pub enum ListChange {
+    Friends {
+        added: Vec<Character>,
+        removed: Vec<Character>,
+    },
+    Bookmarks {
+        added: Vec<Character>,
+        removed: Vec<Character>,
+    },
+    GlobalOps {
+        added: Vec<Character>,
+        removed: Vec<Character>,
+    },
+    ChannelOps {
+        channel: Channel,
+        added: Vec<Character>,
+        removed: Vec<Character>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelListKind {
+    Official,
+    Unofficial,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelListChange {
+    pub list: ChannelListKind,
+    pub added: HashMap<Channel, u32>,
+    pub removed: Vec<Channel>,
+    // Channels that were already listed, with their new head count.
+    pub counts: HashMap<Channel, u32>,
+}
+
+impl ChannelListChange {
+    fn between(
+        list: ChannelListKind,
+        old: &HashMap<Channel, u32>,
+        new: &HashMap<Channel, u32>,
+    ) -> Self {
+        let mut change = ChannelListChange {
+            list,
+            added: HashMap::new(),
+            removed: old
+                .keys()
+                .filter(|v| !new.contains_key(v))
+                .cloned()
+                .collect(),
+            counts: HashMap::new(),
+        };
+        for (channel, count) in new {
+            match old.get(channel) {
+                None => {
+                    change.added.insert(channel.clone(), *count);
+                }
+                Some(old) if old != count => {
+                    change.counts.insert(channel.clone(), *count);
+                }
+                Some(_) => (),
+            }
+        }
+        change
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.counts.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelLists {
+    pub official: Arc<HashMap<Channel, u32>>,
+    pub unofficial: Arc<HashMap<Channel, u32>>,
+}
+
+// Brings `set` in line with `new`, returning what went in and what came out.
+// Nothing is touched that doesn't change, so readers never see it half empty.
+fn replace_set<T: Hash + Eq + Clone>(
+    set: &DashSet<T>,
+    new: impl IntoIterator<Item = T>,
+) -> (Vec<T>, Vec<T>) {
+    let new: HashSet<T> = new.into_iter().collect();
+    let removed: Vec<T> = set
+        .iter()
+        .filter(|v| !new.contains(v.key()))
+        .map(|v| v.key().clone())
+        .collect();
+    for v in removed.iter() {
+        set.remove(v);
+    }
+    let added: Vec<T> = new.into_iter().filter(|v| set.insert(v.clone())).collect();
+    (added, removed)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelMember {
+    pub character: Character,
+    pub gender: Gender,
+    pub status: Status,
+    pub status_message: String,
+    pub role: ChannelRole,
+    pub friend: bool,
+    pub bookmark: bool,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct MemberFilter {
+    pub genders: Option<Vec<Gender>>,
+    pub statuses: Option<Vec<Status>>,
+}
+
+impl MemberFilter {
+    fn matches(&self, member: &ChannelMember) -> bool {
+        self.genders
+            .as_ref()
+            .map_or(true, |v| v.contains(&member.gender))
+            && self
+                .statuses
+                .as_ref()
+                .map_or(true, |v| v.contains(&member.status))
+    }
+}
+
+// The order the official client lists people in, most available first.
+fn status_rank(status: &Status) -> u8 {
+    match data::name(status).as_str() {
+        "looking" => 0,
+        "online" => 1,
+        "crown" => 2,
+        "busy" => 3,
+        "dnd" => 4,
+        "idle" => 5,
+        "away" => 6,
+        _ => 7,
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LastSeen {
+    pub online: bool,
+    pub last_seen: Timestamp,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CharacterSnapshot {
+    pub version: u64,
+    pub characters: HashMap<Character, CharacterDataInner>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CharacterDelta {
+    pub from: u64,
+    pub to: u64,
+    pub added: HashMap<Character, CharacterDataInner>,
+    pub changed: HashMap<Character, CharacterDataInner>,
+    pub removed: Vec<Character>,
 }
 
 #[derive(Debug, Default)]
 struct CacheChannelData {
     pub mode: ChannelMode,
-    pub members: DashSet<Character>,
-    pub ops: DashSet<Character>,
+    pub members: DashSet<CharacterId>,
+    pub ops: DashSet<CharacterId>,
+    // The server sends the owner first in the op list, which is the only way we learn it.
+    pub owner: Option<CharacterId>,
+    pub kind: ChannelKind,
     pub description: String,
     pub title: String,
+    // What get_channel hands out, built on first read and shared until something changes.
+    // Title, description and mode are patched in place; member changes drop it to be rebuilt,
+    // since joins come in bursts and there's no point rebuilding the list for each one.
+    view: Mutex<Option<Arc<ChannelData>>>,
+    // Bumped on every change, so a view built from an older copy isn't kept.
+    generation: AtomicU64,
+    // Whether the member and op lists have come in since we joined. The first of each is us
+    // being told who's there, not anyone joining or being made op. Our own JCH can arrive
+    // before ICH, so an empty list can't be taken to mean "not filled in yet".
+    members_filled: AtomicBool,
+    ops_filled: AtomicBool,
+}
+
+impl CacheChannelData {
+    // Call after changing the members, not before, or a read in between could keep the old list.
+    fn members_changed(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        *self.view.lock().unwrap() = None;
+    }
+
+    // When we leave, the next lists are filling in again.
+    fn own_left(&self) {
+        self.members_filled.store(false, Ordering::Relaxed);
+        self.ops_filled.store(false, Ordering::Relaxed);
+    }
+
+    fn patch_view(&mut self, patch: impl FnOnce(&mut ChannelData)) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        if let Some(view) = self.view.get_mut().unwrap() {
+            patch(Arc::make_mut(view));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelKind {
+    Official,
+    Public,
+    Private,
+}
+
+impl Default for ChannelKind {
+    fn default() -> Self {
+        ChannelKind::Official
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelRole {
+    Member,
+    Op,
+    Owner,
+    GlobalOp,
 }
 
 #[derive(Debug, Default)]
@@ -42,9 +544,754 @@ struct CacheCharacterData {
     pub status_message: String,
 }
 
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct UnreadData {
+    pub last_read: Option<Timestamp>,
+    pub unread: u32,
+    pub mentions: u32,
+}
+
 impl Cache {
     pub fn new() -> Self {
-        Default::default()
+        Cache {
+            retain_offline: AtomicBool::new(true),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_retain_offline(&self, retain: bool) {
+        self.retain_offline.store(retain, Ordering::Relaxed);
+    }
+
+    // Takes the names lock once for the lot, rather than per id.
+    fn resolve_all<C: FromIterator<Character>>(&self, ids: impl Iterator<Item = CharacterId>) -> C {
+        let ids: Vec<CharacterId> = ids.collect();
+        let names = self.interner.names();
+        ids.into_iter().map(|v| names[v.0 as usize]).collect()
+    }
+
+    // A lower limit takes effect as each conversation next gets a message, not all at once.
+    pub fn set_retention(&self, retention: Retention) {
+        *self.retention.write().unwrap() = retention;
+        self.enforce_budget();
+    }
+
+    fn retire(&self, channel: MessageChannel, evicted: Vec<CachedMessage>) {
+        if !evicted.is_empty() && self.retention.read().unwrap().log_evicted {
+            let names = self.interner.names();
+            self.evicted
+                .lock()
+                .unwrap()
+                .extend(evicted.into_iter().map(|v| {
+                    let sent_as = v.context.clone();
+                    let entry = LogEntry {
+                        message: v.resolve(&names),
+                        sent_as,
+                    };
+                    (channel.clone(), entry)
+                }));
+        }
+    }
+
+    // Over budget, the biggest conversation gives up its oldest messages until we're back under.
+    fn enforce_budget(&self) {
+        let budget = self.retention.read().unwrap().budget;
+        while self.message_bytes.load(Ordering::Relaxed) > budget {
+            let largest_messages = self
+                .messages
+                .iter()
+                .max_by_key(|v| v.bytes)
+                .map(|v| (v.key().clone(), v.bytes));
+            let largest_ads = self
+                .ads
+                .iter()
+                .max_by_key(|v| v.bytes)
+                .map(|v| (v.key().clone(), v.bytes));
+
+            let freed = match (largest_messages, largest_ads) {
+                (Some((channel, bytes)), ads) if ads.as_ref().map_or(true, |v| bytes >= v.1) => {
+                    let evicted = self.messages.get_mut(&channel).map(|mut v| {
+                        let count = (v.len() / 10).max(1);
+                        v.evict(count)
+                    });
+                    evicted.map_or(0, |(evicted, freed)| {
+                        self.retire(channel, evicted);
+                        freed
+                    })
+                }
+                (_, Some((channel, _))) => self.ads.get_mut(&channel).map_or(0, |mut v| {
+                    let count = (v.len() / 10).max(1);
+                    v.evict(count).1
+                }),
+                (None, None) => 0,
+            };
+            if freed == 0 {
+                break;
+            }
+            self.message_bytes.fetch_sub(freed, Ordering::Relaxed);
+        }
+    }
+
+    pub fn take_evicted(&self) -> Vec<(MessageChannel, LogEntry)> {
+        std::mem::take(&mut *self.evicted.lock().unwrap())
+    }
+
+    // Entries and approximate bytes for each of the big maps.
+    // Sizes count the maps' own storage and the text they hold, not allocator overhead.
+    pub fn stats(&self) -> BTreeMap<&'static str, MapStats> {
+        fn buffers<K: std::hash::Hash + Eq>(map: &DashMap<K, MessageBuffer>) -> MapStats {
+            map.iter().fold(MapStats::default(), |stats, v| MapStats {
+                entries: stats.entries + v.len(),
+                bytes: stats.bytes + size_of::<K>() + v.bytes,
+            })
+        }
+
+        let mut stats = BTreeMap::new();
+        stats.insert(
+            "channels",
+            self.channels
+                .iter()
+                .fold(MapStats::default(), |stats, v| MapStats {
+                    entries: stats.entries + 1,
+                    bytes: stats.bytes
+                        + size_of::<Channel>()
+                        + size_of::<CacheChannelData>()
+                        + v.title.len()
+                        + v.description.len()
+                        + (v.members.len() + v.ops.len()) * size_of::<CharacterId>(),
+                }),
+        );
+        stats.insert(
+            "characters",
+            self.characters
+                .iter()
+                .fold(MapStats::default(), |stats, v| MapStats {
+                    entries: stats.entries + 1,
+                    bytes: stats.bytes
+                        + size_of::<CharacterId>()
+                        + size_of::<CacheCharacterData>()
+                        + v.status_message.len(),
+                }),
+        );
+        stats.insert(
+            "names",
+            MapStats {
+                entries: self.interner.len(),
+                // Once in the table, once as the key for looking it back up.
+                bytes: self.interner.len()
+                    * (2 * size_of::<Character>() + size_of::<CharacterId>()),
+            },
+        );
+        stats.insert("messages", buffers(&self.messages));
+        stats.insert("ads", buffers(&self.ads));
+        stats.insert(
+            "unread",
+            MapStats {
+                entries: self.unread.len(),
+                bytes: self.unread.len() * (size_of::<MessageChannel>() + size_of::<UnreadData>()),
+            },
+        );
+        stats.insert(
+            "last_seen",
+            MapStats {
+                entries: self.last_seen.len(),
+                bytes: self.last_seen.len() * (size_of::<CharacterId>() + size_of::<Timestamp>()),
+            },
+        );
+        let history: usize = self.channel_history.iter().map(|v| v.len()).sum();
+        stats.insert(
+            "channel_history",
+            MapStats {
+                entries: history,
+                bytes: history * size_of::<ChannelChange>(),
+            },
+        );
+        let log = self.character_log.lock().unwrap().changes.len();
+        stats.insert(
+            "character_log",
+            MapStats {
+                entries: log,
+                bytes: log * size_of::<(u64, CharacterId, CharacterChange)>(),
+            },
+        );
+        stats
+    }
+
+    pub fn get_last_seen(&self, character: &Character) -> Option<LastSeen> {
+        let id = self.interner.id(character)?;
+        self.last_seen.get(&id).map(|v| LastSeen {
+            online: self
+                .characters
+                .get(&id)
+                .map_or(false, |v| v.status != Status::Offline),
+            last_seen: *v,
+        })
+    }
+
+    pub fn set_own_characters(&self, characters: &[Character]) {
+        for character in characters {
+            self.own.insert(self.interner.intern(character));
+        }
+    }
+
+    // Online, offline and status transitions since the last call, in the order they happened.
+    // Nothing is recorded for the initial list; everyone in it was already online.
+    pub fn take_presence_changes(&self) -> Vec<PresenceChange> {
+        std::mem::take(&mut *self.presence.lock().unwrap())
+    }
+
+    // None if we don't know the channel at all.
+    pub fn channel_role(&self, channel: &Channel, character: &Character) -> Option<ChannelRole> {
+        let id = self.interner.id(character);
+        let chan = self.channels.get(channel)?;
+        Some(id.map_or(ChannelRole::Member, |id| self.role_in(&chan, id)))
+    }
+
+    fn role_in(&self, chan: &CacheChannelData, character: CharacterId) -> ChannelRole {
+        if self.admins.contains(&character) {
+            ChannelRole::GlobalOp
+        } else if chan.owner == Some(character) {
+            ChannelRole::Owner
+        } else if chan.ops.contains(&character) {
+            ChannelRole::Op
+        } else {
+            ChannelRole::Member
+        }
+    }
+
+    // Attributes the next change to the channel to this character.
+    // Used when an own session changes something, since the server won't say who did.
+    pub fn expect_channel_change(&self, channel: Channel, by: Character) {
+        self.pending_channel_changes
+            .insert(channel, (by, Utc::now()));
+    }
+
+    fn record_channel_changes(&self, channel: Channel, changes: Vec<ChannelChangeKind>) {
+        let at = Utc::now();
+        let by = self
+            .pending_channel_changes
+            .remove(&channel)
+            .map(|v| v.1)
+            .filter(|(_, since)| at - *since < Duration::seconds(PENDING_CHANGE_SECS))
+            .map(|(by, _)| by);
+        let changes: Vec<ChannelChange> = changes
+            .into_iter()
+            .map(|change| ChannelChange {
+                channel: channel.clone(),
+                at,
+                by,
+                change,
+            })
+            .collect();
+
+        let mut history = self.channel_history.entry(channel).or_default();
+        history.extend(changes.iter().cloned());
+        while history.len() > CHANNEL_HISTORY_LIMIT {
+            history.pop_front();
+        }
+        drop(history);
+        self.channel_changes.lock().unwrap().extend(changes);
+    }
+
+    // Oldest first.
+    pub fn get_channel_history(&self, channel: &Channel) -> Vec<ChannelChange> {
+        self.channel_history
+            .get(channel)
+            .map_or(Vec::new(), |v| v.iter().cloned().collect())
+    }
+
+    // Title, description and mode changes since the last call, in the order they happened.
+    pub fn take_channel_changes(&self) -> Vec<ChannelChange> {
+        std::mem::take(&mut *self.channel_changes.lock().unwrap())
+    }
+
+    fn record_member_change(&self, channel: &Channel, character: Character, joined: bool) {
+        self.member_changes.lock().unwrap().push(MemberChange {
+            channel: channel.clone(),
+            character,
+            joined,
+        });
+    }
+
+    // Joins and leaves since the last call, in the order they happened.
+    pub fn take_member_changes(&self) -> Vec<MemberChange> {
+        std::mem::take(&mut *self.member_changes.lock().unwrap())
+    }
+
+    fn channel_list(&self, list: ChannelListKind) -> &RwLock<Arc<HashMap<Channel, u32>>> {
+        match list {
+            ChannelListKind::Official => &self.global_channels,
+            ChannelListKind::Unofficial => &self.unofficial_channels,
+        }
+    }
+
+    // Swaps the list out whole, so a reader sees either the old one or the new one.
+    // Returns what changed, if anything did, having queued it for the frontend.
+    fn replace_channel_list(
+        &self,
+        list: ChannelListKind,
+        channels: Vec<(Channel, u32)>,
+    ) -> Option<ChannelListChange> {
+        let new: HashMap<Channel, u32> = channels.into_iter().collect();
+        let change = {
+            let mut current = self.channel_list(list).write().unwrap();
+            let change = ChannelListChange::between(list, &current, &new);
+            if change.is_empty() {
+                return None;
+            }
+            *current = Arc::new(new);
+            change
+        };
+        self.channel_list_changes
+            .lock()
+            .unwrap()
+            .push(change.clone());
+        Some(change)
+    }
+
+    pub fn channel_lists(&self) -> ChannelLists {
+        ChannelLists {
+            official: self.global_channels.read().unwrap().clone(),
+            unofficial: self.unofficial_channels.read().unwrap().clone(),
+        }
+    }
+
+    // Room list changes since the last call, in the order they happened.
+    pub fn take_channel_list_changes(&self) -> Vec<ChannelListChange> {
+        std::mem::take(&mut *self.channel_list_changes.lock().unwrap())
+    }
+
+    fn record_list_change(&self, change: ListChange) {
+        self.list_changes.lock().unwrap().push(change);
+    }
+
+    // Builds the change from interned ids; nothing is recorded if both sides are empty.
+    fn record_list_diff(
+        &self,
+        added: Vec<CharacterId>,
+        removed: Vec<CharacterId>,
+        change: impl FnOnce(Vec<Character>, Vec<Character>) -> ListChange,
+    ) -> bool {
+        if added.is_empty() && removed.is_empty() {
+            return false;
+        }
+        let added = self.resolve_all(added.into_iter());
+        let removed = self.resolve_all(removed.into_iter());
+        self.record_list_change(change(added, removed));
+        true
+    }
+
+    // Friend, bookmark and op list changes since the last call, in the order they happened.
+    pub fn take_list_changes(&self) -> Vec<ListChange> {
+        std::mem::take(&mut *self.list_changes.lock().unwrap())
+    }
+
+    fn channel_member(
+        &self,
+        chan: &CacheChannelData,
+        character: CharacterId,
+        names: &[Character],
+    ) -> ChannelMember {
+        let role = self.role_in(chan, character);
+        let data = self.characters.get(&character);
+        ChannelMember {
+            character: names[character.0 as usize],
+            gender: data.as_ref().map(|v| v.gender).unwrap_or_default(),
+            status: data.as_ref().map(|v| v.status).unwrap_or_default(),
+            status_message: data.map(|v| v.status_message.clone()).unwrap_or_default(),
+            role,
+            friend: self.has_friend(character),
+            bookmark: self.bookmarks.contains(&character),
+        }
+    }
+
+    pub fn get_channel_member(
+        &self,
+        channel: &Channel,
+        character: Character,
+    ) -> Option<ChannelMember> {
+        let id = self.interner.id(&character)?;
+        let chan = self.channels.get(channel)?;
+        Some(self.channel_member(&chan, id, &self.interner.names()))
+    }
+
+    // Ops first, then by status, then by name; the way the official client shows them.
+    pub fn get_channel_members(
+        &self,
+        channel: &Channel,
+        filter: &MemberFilter,
+    ) -> Option<Vec<ChannelMember>> {
+        let chan = self.channels.get(channel)?;
+        let names = self.interner.names();
+        let mut members: Vec<(String, ChannelMember)> = chan
+            .members
+            .iter()
+            .map(|v| self.channel_member(&chan, *v, &names))
+            .filter(|v| filter.matches(v))
+            .map(|v| (data::name(&v.character).to_lowercase(), v))
+            .collect();
+        drop(names);
+        drop(chan);
+
+        members.sort_by(|(a_name, a), (b_name, b)| {
+            (a.role == ChannelRole::Member)
+                .cmp(&(b.role == ChannelRole::Member))
+                .then_with(|| status_rank(&a.status).cmp(&status_rank(&b.status)))
+                .then_with(|| a_name.cmp(b_name))
+        });
+        Some(members.into_iter().map(|(_, v)| v).collect())
+    }
+
+    // Cheap unless the members changed since the last read, in which case the list is rebuilt once.
+    // A miss copies the member ids out and builds the list with no guard held; a big room's
+    // member list shouldn't hold up everything else that hashes to the same shard.
+    pub fn channel_view(&self, channel: &Channel) -> Option<Arc<ChannelData>> {
+        let (generation, ids, mode, description, title) = {
+            let chan = self.channels.get(channel)?;
+            if let Some(view) = chan.view.lock().unwrap().as_ref() {
+                return Some(view.clone());
+            }
+            // Read before copying, so a change during the copy counts as newer than it.
+            let generation = chan.generation.load(Ordering::SeqCst);
+            let ids: Vec<CharacterId> = chan.members.iter().map(|v| *v).collect();
+            (
+                generation,
+                ids,
+                chan.mode,
+                chan.description.clone(),
+                chan.title.clone(),
+            )
+        };
+        let data = Arc::new(ChannelData {
+            channel: channel.to_owned(),
+            channel_mode: mode,
+            members: self.resolve_all(ids.into_iter()),
+            description,
+            title,
+        });
+        // Anything that changed in the meantime means this is already out of date; it's still
+        // fine to hand out, but the next read builds a fresh one.
+        if let Some(chan) = self.channels.get(channel) {
+            let mut view = chan.view.lock().unwrap();
+            if view.is_none() && chan.generation.load(Ordering::SeqCst) == generation {
+                *view = Some(data.clone());
+            }
+        }
+        Some(data)
+    }
+
+    fn current_title(&self, channel: &Channel) -> Option<String> {
+        self.channels
+            .get(channel)
+            .map(|v| v.title.clone())
+            .filter(|v| !v.is_empty())
+    }
+
+    // The newest `limit` messages, oldest first, each with what it was sent under and what that is now.
+    pub fn get_message_records(
+        &self,
+        source: &MessageChannel,
+        limit: Option<usize>,
+    ) -> Vec<MessageRecord> {
+        let cached: Vec<CachedMessage> = match self.messages.get(source) {
+            Some(messages) => {
+                let skip = limit.map_or(0, |limit| messages.len().saturating_sub(limit));
+                messages.iter().skip(skip).cloned().collect()
+            }
+            None => return Vec::new(),
+        };
+        let title: Option<Arc<str>> = match source {
+            MessageChannel::Channel(channel) => self.current_title(channel).map(Arc::from),
+            MessageChannel::PrivateMessage(..) => None,
+        };
+        let names = self.interner.names();
+        cached
+            .into_iter()
+            .map(|v| {
+                let current = MessageContext {
+                    title: title.clone(),
+                    gender: self.characters.get(&v.character).map(|v| v.gender),
+                };
+                let sent_as = v.context.clone();
+                MessageRecord {
+                    message: v.resolve(&names),
+                    sent_as,
+                    current,
+                }
+            })
+            .collect()
+    }
+
+    // What a message from this character would be sent under now. For messages the cache
+    // no longer holds, like those read back from the logs.
+    pub fn current_context(
+        &self,
+        source: &MessageChannel,
+        character: &Character,
+    ) -> MessageContext {
+        MessageContext {
+            title: match source {
+                MessageChannel::Channel(channel) => self.current_title(channel).map(Arc::from),
+                MessageChannel::PrivateMessage(..) => None,
+            },
+            gender: self
+                .interner
+                .id(character)
+                .and_then(|id| self.characters.get(&id).map(|v| v.gender)),
+        }
+    }
+
+    pub fn roll_stats(&self, channel: &MessageChannel, since: Option<Timestamp>) -> RollStats {
+        self.rolls.stats(channel, since)
+    }
+
+    pub fn channel_views(&self) -> Vec<Arc<ChannelData>> {
+        let channels: Vec<Channel> = self.channels.iter().map(|v| v.key().clone()).collect();
+        channels
+            .iter()
+            .filter_map(|v| self.channel_view(v))
+            .collect()
+    }
+
+    pub fn get_channel_kind(&self, channel: &Channel) -> Option<ChannelKind> {
+        self.channels.get(channel).map(|v| v.kind)
+    }
+
+    pub fn set_channel_kind(&self, channel: &Channel, kind: ChannelKind) -> bool {
+        self.channels
+            .get_mut(channel)
+            .map_or(false, |mut v| std::mem::replace(&mut v.kind, kind) != kind)
+    }
+
+    pub fn is_bookmark(&self, character: &Character) -> bool {
+        self.interner
+            .id(character)
+            .map_or(false, |v| self.bookmarks.contains(&v))
+    }
+
+    pub fn is_friend(&self, character: &Character) -> bool {
+        self.interner
+            .id(character)
+            .map_or(false, |v| self.has_friend(v))
+    }
+
+    fn has_friend(&self, id: CharacterId) -> bool {
+        self.friends.iter().any(|v| v.1 == id)
+    }
+
+    // Friends of any of our characters, each only once.
+    fn friend_ids(&self) -> HashSet<CharacterId> {
+        self.friends.iter().map(|v| v.1).collect()
+    }
+
+    pub fn friend_set(&self) -> HashSet<Character> {
+        self.resolve_all(self.friend_ids().into_iter())
+    }
+
+    fn character_offline(&self, character: Character, was_online: bool) -> bool {
+        let id = self.interner.intern(&character);
+        let now = Utc::now();
+        self.last_seen.insert(id, now);
+        let mut channels = Vec::new();
+        let own = self.own.contains(&id);
+        for chan in self.channels.iter() {
+            if chan.members.remove(&id).is_some() {
+                if own {
+                    chan.own_left();
+                }
+                chan.members_changed();
+                channels.push(chan.key().clone());
+            }
+        }
+        for channel in channels.iter() {
+            self.record_member_change(channel, character, false);
+        }
+
+        let retain = self.retain_offline.load(Ordering::Relaxed)
+            && (self.bookmarks.contains(&id) || self.has_friend(id));
+        let changed = if retain {
+            let mut v = self.characters.entry(id).or_default();
+            let changed = v.status != Status::Offline;
+            v.status = Status::Offline;
+            drop(v);
+            if changed {
+                self.log_character(id, CharacterChange::Changed);
+            }
+            changed
+        } else if self.characters.remove(&id).is_some() {
+            self.log_character(id, CharacterChange::Removed);
+            true
+        } else {
+            false
+        };
+
+        if was_online && self.listed.load(Ordering::Relaxed) {
+            self.presence.lock().unwrap().push(PresenceChange {
+                character,
+                kind: PresenceKind::Offline,
+                status: Status::Offline,
+                status_message: String::new(),
+                at: now,
+                channels,
+            });
+        }
+        changed || was_online
+    }
+
+    // Called for each incoming message after it's been inserted.
+    // Own messages should be passed to mark_read instead; you've read what you wrote.
+    pub fn record_unread(
+        &self,
+        channel: &MessageChannel,
+        message: &Message,
+        mention: bool,
+    ) -> UnreadData {
+        let mut data = self.unread.entry(channel.clone()).or_default();
+        if data.last_read.map_or(true, |v| message.timestamp > v) {
+            data.unread += 1;
+            if mention {
+                data.mentions += 1;
+            }
+        }
+        data.clone()
+    }
+
+    // With no position, everything currently in the cache is marked read.
+    pub fn mark_read(&self, channel: MessageChannel, up_to: Option<Timestamp>) -> UnreadData {
+        let messages = self.messages.get(&channel);
+        let up_to = up_to
+            .or_else(|| {
+                messages
+                    .as_ref()
+                    .and_then(|v| v.last())
+                    .map(|v| v.timestamp)
+            })
+            .unwrap_or_else(Utc::now);
+        let remaining = messages.map_or(0, |v| {
+            v.iter().rev().take_while(|v| v.timestamp > up_to).count() as u32
+        });
+
+        let mut data = self.unread.entry(channel).or_default();
+        data.last_read = Some(data.last_read.map_or(up_to, |v| v.max(up_to)));
+        data.unread = remaining;
+        // We don't know which of the remaining were mentions, but there can't be more than are left.
+        data.mentions = data.mentions.min(remaining);
+        data.clone()
+    }
+
+    pub fn get_unread_summary(&self) -> Vec<(MessageChannel, UnreadData)> {
+        self.unread
+            .iter()
+            .filter(|v| v.unread > 0)
+            .map(|v| (v.key().clone(), v.value().clone()))
+            .collect()
+    }
+
+    // Only the markers are persisted; counts are rebuilt from whatever arrives next session.
+    pub fn read_markers(&self) -> Vec<(MessageChannel, Timestamp)> {
+        self.unread
+            .iter()
+            .filter_map(|v| v.last_read.map(|last_read| (v.key().clone(), last_read)))
+            .collect()
+    }
+
+    pub fn character_version(&self) -> u64 {
+        self.character_log.lock().unwrap().version
+    }
+
+    fn log_character(&self, character: CharacterId, change: CharacterChange) {
+        let mut log = self.character_log.lock().unwrap();
+        log.version += 1;
+        let version = log.version;
+        log.changes.push_back((version, character, change));
+        if log.changes.len() > CHARACTER_LOG_LIMIT {
+            log.changes.pop_front();
+        }
+    }
+
+    fn character_inner(&self, character: CharacterId) -> Option<CharacterDataInner> {
+        self.characters.get(&character).map(|v| CharacterDataInner {
+            gender: v.gender,
+            status: v.status,
+            status_message: v.status_message.clone(),
+        })
+    }
+
+    // The version is taken before copying, so anything that changes mid-copy
+    // will also turn up in the next delta. Applying it twice is harmless.
+    pub fn character_snapshot(&self) -> CharacterSnapshot {
+        let version = self.character_version();
+        let data: Vec<(CharacterId, CharacterDataInner)> = self
+            .characters
+            .iter()
+            .map(|v| {
+                (
+                    *v.key(),
+                    CharacterDataInner {
+                        gender: v.gender,
+                        status: v.status,
+                        status_message: v.status_message.clone(),
+                    },
+                )
+            })
+            .collect();
+        let names = self.interner.names();
+        CharacterSnapshot {
+            version,
+            characters: data
+                .into_iter()
+                .map(|(id, data)| (names[id.0 as usize], data))
+                .collect(),
+        }
+    }
+
+    // Everything that happened after `since`, folded down to one entry per character.
+    // None if the log no longer reaches back that far, and the caller should resync.
+    pub fn character_delta(&self, since: u64) -> Option<CharacterDelta> {
+        let (to, changes) = {
+            let log = self.character_log.lock().unwrap();
+            let oldest = log.changes.front().map_or(log.version, |v| v.0 - 1);
+            if since < oldest || since > log.version {
+                return None;
+            }
+            let mut changes: HashMap<CharacterId, CharacterChange> = HashMap::new();
+            for (_, character, change) in log.changes.iter().filter(|v| v.0 > since) {
+                let entry = changes.entry(*character).or_insert(*change);
+                // Added stays added (the frontend has never seen it), anything else takes the latest.
+                if *entry != CharacterChange::Added || *change == CharacterChange::Removed {
+                    *entry = *change;
+                }
+            }
+            (log.version, changes)
+        };
+
+        let mut delta = CharacterDelta {
+            from: since,
+            to,
+            ..Default::default()
+        };
+        for (id, change) in changes {
+            let character = self.interner.name(id);
+            match (change, self.character_inner(id)) {
+                (CharacterChange::Removed, _) | (_, None) => delta.removed.push(character),
+                (CharacterChange::Added, Some(data)) => {
+                    delta.added.insert(character, data);
+                }
+                (CharacterChange::Changed, Some(data)) => {
+                    delta.changed.insert(character, data);
+                }
+            }
+        }
+        Some(delta)
+    }
+
+    pub fn load_read_markers(
+        &self,
+        markers: impl IntoIterator<Item = (MessageChannel, Timestamp)>,
+    ) {
+        for (channel, last_read) in markers {
+            self.unread.entry(channel).or_default().last_read = Some(last_read);
+        }
     }
 }
 
@@ -59,13 +1306,44 @@ impl f_chat_rs::cache::Cache for Cache {
         source: MessageChannel,
         message: Message,
     ) -> Result<bool, Self::Error> {
-        let mut messages = self.messages.entry(source).or_insert(Vec::new());
-        if messages.last() == Some(&message) {
-            Ok(false)
-        } else {
-            messages.push(message);
-            Ok(true)
+        let per_channel = self.retention.read().unwrap().per_channel;
+        let character = self.interner.intern(&message.character);
+        let title = match &source {
+            MessageChannel::Channel(channel) => self.current_title(channel),
+            MessageChannel::PrivateMessage(..) => None,
+        };
+        let gender = self.characters.get(&character).map(|v| v.gender);
+        let roll = match message.content {
+            MessageContent::Roll(..) | MessageContent::Bottle(_) => Some(message.clone()),
+            _ => None,
+        };
+        let evicted = {
+            let mut messages = self.messages.entry(source.clone()).or_default();
+            let title = match (title, messages.last().and_then(|v| v.context.title.clone())) {
+                (Some(title), Some(last)) if *last == *title => Some(last),
+                (title, _) => title.map(Arc::from),
+            };
+            let message = CachedMessage {
+                timestamp: message.timestamp,
+                character,
+                content: message.content,
+                context: MessageContext { title, gender },
+            };
+            if messages.last() == Some(&message) {
+                return Ok(false);
+            }
+            let added = messages.push(message);
+            let (evicted, freed) = messages.evict(eviction_count(messages.len(), per_channel));
+            self.message_bytes.fetch_add(added, Ordering::Relaxed);
+            self.message_bytes.fetch_sub(freed, Ordering::Relaxed);
+            evicted
+        };
+        if let Some(roll) = roll {
+            self.rolls.record(&source, &roll);
         }
+        self.retire(source, evicted);
+        self.enforce_budget();
+        Ok(true)
     }
 
     fn insert_channel(
@@ -78,14 +1356,35 @@ impl f_chat_rs::cache::Cache for Cache {
             // If it already exists, it should be consistent.
             Ok(false)
         } else {
+            // Rooms made by users are all ADH-something. Whether one is public depends on
+            // whether it's in the public room list, which may yet change (see set_unofficial_channels).
+            let members = members.iter().map(|v| self.interner.intern(v)).collect();
+            let kind = if !data::name(channel.as_ref()).starts_with("ADH-") {
+                ChannelKind::Official
+            } else if self
+                .unofficial_channels
+                .read()
+                .unwrap()
+                .contains_key(channel.as_ref())
+            {
+                ChannelKind::Public
+            } else {
+                ChannelKind::Private
+            };
             self.channels.insert(
                 channel.into_owned(),
                 CacheChannelData {
                     mode: data.mode.unwrap_or_default(),
-                    members: members.iter().cloned().collect(),
+                    members,
                     description: data.description.unwrap_or_default().into_owned(),
                     title: data.title.unwrap_or_default().into_owned(),
                     ops: DashSet::new(),
+                    owner: None,
+                    kind,
+                    view: Mutex::new(None),
+                    generation: AtomicU64::new(0),
+                    members_filled: AtomicBool::new(false),
+                    ops_filled: AtomicBool::new(false),
                 },
             );
             Ok(true)
@@ -98,21 +1397,34 @@ impl f_chat_rs::cache::Cache for Cache {
         character: Cow<Character>,
         ad: Cow<str>,
     ) -> Result<bool, Self::Error> {
-        let mut ads = self.ads.entry(channel.into_owned()).or_default();
-        if let Some(Message {
-            content: MessageContent::Message(inner_ad),
-            ..
-        }) = ads.last()
+        let per_channel = self.retention.read().unwrap().per_channel;
+        let character = self.interner.intern(&character);
+        let gender = self.characters.get(&character).map(|v| v.gender);
         {
-            if inner_ad == ad.as_ref() {
-                return Ok(false);
+            let mut ads = self.ads.entry(channel.into_owned()).or_default();
+            if let Some(CachedMessage {
+                content: MessageContent::Message(inner_ad),
+                ..
+            }) = ads.last()
+            {
+                if inner_ad == ad.as_ref() {
+                    return Ok(false);
+                }
             }
+            let added = ads.push(CachedMessage {
+                timestamp: Utc::now(),
+                character,
+                content: MessageContent::Message(ad.into_owned()),
+                context: MessageContext {
+                    title: None,
+                    gender,
+                },
+            });
+            let (_, freed) = ads.evict(eviction_count(ads.len(), per_channel));
+            self.message_bytes.fetch_add(added, Ordering::Relaxed);
+            self.message_bytes.fetch_sub(freed, Ordering::Relaxed);
         }
-        ads.push(Message {
-            timestamp: Utc::now(),
-            character: character.into_owned(),
-            content: MessageContent::Message(ad.into_owned()),
-        });
+        self.enforce_budget();
         Ok(true)
     }
 
@@ -121,11 +1433,16 @@ impl f_chat_rs::cache::Cache for Cache {
         channel: Cow<Channel>,
         member: Character,
     ) -> Result<bool, Self::Error> {
-        if let Some(chan) = self.channels.get(&channel) {
-            Ok(chan.members.insert(member))
-        } else {
-            Ok(false)
+        let id = self.interner.intern(&member);
+        let joined = self.channels.get(&channel).map_or(false, |chan| {
+            let joined = chan.members.insert(id);
+            chan.members_changed();
+            joined
+        });
+        if joined {
+            self.record_member_change(&channel, member, true);
         }
+        Ok(joined)
     }
 
     fn remove_channel_member(
@@ -133,27 +1450,70 @@ impl f_chat_rs::cache::Cache for Cache {
         channel: Cow<Channel>,
         member: Character,
     ) -> Result<bool, Self::Error> {
-        if let Some(chan) = self.channels.get(&channel) {
-            Ok(chan.members.remove(&member).is_some())
-        } else {
-            Ok(false)
+        let left = self.interner.id(&member).map_or(false, |id| {
+            self.channels.get(&channel).map_or(false, |chan| {
+                let left = chan.members.remove(&id).is_some();
+                if self.own.contains(&id) {
+                    chan.own_left();
+                }
+                chan.members_changed();
+                left
+            })
+        });
+        if left {
+            self.record_member_change(&channel, member, false);
         }
+        Ok(left)
     }
 
     fn add_bookmark(&self, character: Cow<Character>) -> Result<bool, Self::Error> {
-        Ok(self.bookmarks.insert(character.into_owned()))
+        let added = self.bookmarks.insert(self.interner.intern(&character));
+        if added {
+            self.record_list_change(ListChange::Bookmarks {
+                added: vec![character.into_owned()],
+                removed: Vec::new(),
+            });
+        }
+        Ok(added)
     }
 
     fn remove_bookmark(&self, character: Cow<Character>) -> Result<bool, Self::Error> {
-        Ok(self.bookmarks.remove(&character).is_some())
+        let removed = self
+            .interner
+            .id(&character)
+            .map_or(false, |v| self.bookmarks.remove(&v).is_some());
+        if removed {
+            self.record_list_change(ListChange::Bookmarks {
+                added: Vec::new(),
+                removed: vec![character.into_owned()],
+            });
+        }
+        Ok(removed)
     }
 
     fn add_global_op(&self, character: Cow<Character>) -> Result<bool, Self::Error> {
-        Ok(self.admins.insert(character.into_owned()))
+        let added = self.admins.insert(self.interner.intern(&character));
+        if added {
+            self.record_list_change(ListChange::GlobalOps {
+                added: vec![character.into_owned()],
+                removed: Vec::new(),
+            });
+        }
+        Ok(added)
     }
 
     fn remove_global_op(&self, character: Cow<Character>) -> Result<bool, Self::Error> {
-        Ok(self.admins.remove(&character).is_some())
+        let removed = self
+            .interner
+            .id(&character)
+            .map_or(false, |v| self.admins.remove(&v).is_some());
+        if removed {
+            self.record_list_change(ListChange::GlobalOps {
+                added: Vec::new(),
+                removed: vec![character.into_owned()],
+            });
+        }
+        Ok(removed)
     }
 
     fn add_channel_op(
@@ -161,11 +1521,19 @@ impl f_chat_rs::cache::Cache for Cache {
         channel: Cow<Channel>,
         character: Cow<Character>,
     ) -> Result<bool, Self::Error> {
-        if let Some(chan) = self.channels.get(&channel) {
-            Ok(chan.ops.insert(character.into_owned()))
-        } else {
-            Ok(false)
+        let id = self.interner.intern(&character);
+        let added = self
+            .channels
+            .get(&channel)
+            .map_or(false, |chan| chan.ops.insert(id));
+        if added {
+            self.record_list_change(ListChange::ChannelOps {
+                channel: channel.into_owned(),
+                added: vec![character.into_owned()],
+                removed: Vec::new(),
+            });
         }
+        Ok(added)
     }
 
     fn remove_channel_op(
@@ -173,11 +1541,18 @@ impl f_chat_rs::cache::Cache for Cache {
         channel: Cow<Channel>,
         character: Cow<Character>,
     ) -> Result<bool, Self::Error> {
-        if let Some(chan) = self.channels.get(&channel) {
-            Ok(chan.ops.remove(&character).is_some())
-        } else {
-            Ok(false)
+        let removed = match (self.interner.id(&character), self.channels.get(&channel)) {
+            (Some(id), Some(chan)) => chan.ops.remove(&id).is_some(),
+            _ => false,
+        };
+        if removed {
+            self.record_list_change(ListChange::ChannelOps {
+                channel: channel.into_owned(),
+                added: Vec::new(),
+                removed: vec![character.into_owned()],
+            });
         }
+        Ok(removed)
     }
 
     fn update_channel(
@@ -186,26 +1561,56 @@ impl f_chat_rs::cache::Cache for Cache {
         data: PartialChannelData,
     ) -> Result<bool, Self::Error> {
         let mut changed = false;
-        self.channels.entry(channel.into_owned()).and_modify(|v| {
+        let mut changes = Vec::new();
+        let channel = channel.into_owned();
+        self.channels.entry(channel.clone()).and_modify(|v| {
+            // A blank old value is the channel being filled in after joining, not a change.
             if let Some(title) = data.title {
                 if &title != &v.title {
                     changed = true;
-                    v.title = title.into_owned();
+                    let old = std::mem::replace(&mut v.title, title.into_owned());
+                    if !old.is_empty() {
+                        changes.push(ChannelChangeKind::Title {
+                            old,
+                            new: v.title.clone(),
+                        });
+                    }
                 }
             }
             if let Some(desc) = data.description {
                 if &desc != &v.description {
                     changed = true;
-                    v.description = desc.into_owned();
+                    let old = std::mem::replace(&mut v.description, desc.into_owned());
+                    if !old.is_empty() {
+                        changes.push(ChannelChangeKind::Description {
+                            old,
+                            new: v.description.clone(),
+                        });
+                    }
                 }
             }
             if let Some(mode) = data.mode {
                 if mode != v.mode {
                     changed = true;
+                    changes.push(ChannelChangeKind::Mode {
+                        old: v.mode,
+                        new: mode,
+                    });
                     v.mode = mode;
                 }
             }
+            if changed {
+                let (title, description, mode) = (v.title.clone(), v.description.clone(), v.mode);
+                v.patch_view(|view| {
+                    view.title = title;
+                    view.description = description;
+                    view.channel_mode = mode;
+                });
+            }
         });
+        if !changes.is_empty() {
+            self.record_channel_changes(channel, changes);
+        }
         Ok(changed)
     }
 
@@ -214,8 +1619,23 @@ impl f_chat_rs::cache::Cache for Cache {
         character: Cow<Character>,
         data: PartialUserData,
     ) -> Result<bool, Self::Error> {
+        let character = character.into_owned();
+        let id = self.interner.intern(&character);
+        let was_online = self
+            .characters
+            .get(&id)
+            .map_or(false, |v| v.status != Status::Offline);
+        if data.status == Some(Status::Offline) {
+            return Ok(self.character_offline(character, was_online));
+        }
+
         let mut changed = false;
-        let mut v = self.characters.entry(character.into_owned()).or_default();
+        let mut status_changed = false;
+        let mut added = false;
+        let mut v = self.characters.entry(id).or_insert_with(|| {
+            added = true;
+            Default::default()
+        });
         if let Some(gender) = data.gender {
             if v.gender != gender {
                 changed = true;
@@ -225,34 +1645,82 @@ impl f_chat_rs::cache::Cache for Cache {
         if let Some(status) = data.status {
             if v.status != status {
                 changed = true;
+                status_changed = true;
                 v.status = status;
             }
         }
         if let Some(message) = data.status_message {
             if &v.status_message != &message {
                 changed = true;
+                status_changed = true;
                 v.status_message = message.into_owned();
             }
         }
-        Ok(changed)
+        let (status, status_message) = (v.status, v.status_message.clone());
+        drop(v);
+        let now = Utc::now();
+        self.last_seen.insert(id, now);
+        if !was_online && self.own.contains(&id) {
+            self.listed.store(true, Ordering::Relaxed);
+        }
+        if (!was_online || status_changed) && self.listed.load(Ordering::Relaxed) {
+            self.presence.lock().unwrap().push(PresenceChange {
+                character,
+                kind: if was_online {
+                    PresenceKind::Status
+                } else {
+                    PresenceKind::Online
+                },
+                status,
+                status_message,
+                at: now,
+                channels: Vec::new(),
+            });
+        }
+        if added {
+            self.log_character(id, CharacterChange::Added);
+        } else if changed {
+            self.log_character(id, CharacterChange::Changed);
+        }
+        Ok(changed || added)
     }
 
+    // The server resends the whole list on reconnect, which is usually no change at all.
+    // Changes are reported by friend, so one friend of two of our characters only counts once.
     fn set_friends(&self, friends: Cow<[FriendRelation]>) -> Result<bool, Self::Error> {
-        // This is lazy and will always update. Come up with a smarter way later.
-        self.friends.clear();
-        for v in friends.into_owned().drain(..) {
-            self.friends.insert(v);
+        let friends: Vec<(CharacterId, CharacterId)> = friends
+            .iter()
+            .map(|v| {
+                (
+                    self.interner.intern(&v.own_character),
+                    self.interner.intern(&v.other_character),
+                )
+            })
+            .collect();
+        let before = self.friend_ids();
+        let (added, removed) = replace_set(&self.friends, friends);
+        if added.is_empty() && removed.is_empty() {
+            return Ok(false);
         }
+        let after = self.friend_ids();
+        self.record_list_diff(
+            after.difference(&before).copied().collect(),
+            before.difference(&after).copied().collect(),
+            |added, removed| ListChange::Friends { added, removed },
+        );
         Ok(true)
     }
 
     fn set_bookmarks(&self, bookmarks: Cow<[Character]>) -> Result<bool, Self::Error> {
-        // This is also lazy and will always update.
-        self.bookmarks.clear();
-        for v in bookmarks.into_owned().drain(..) {
-            self.bookmarks.insert(v);
-        }
-        Ok(true)
+        let bookmarks: Vec<CharacterId> =
+            bookmarks.iter().map(|v| self.interner.intern(v)).collect();
+        let (added, removed) = replace_set(&self.bookmarks, bookmarks);
+        Ok(
+            self.record_list_diff(added, removed, |added, removed| ListChange::Bookmarks {
+                added,
+                removed,
+            }),
+        )
     }
 
     fn set_channel_members(
@@ -260,43 +1728,68 @@ impl f_chat_rs::cache::Cache for Cache {
         channel: Cow<Channel>,
         members: Cow<[Character]>,
     ) -> Result<bool, Self::Error> {
-        // Later, evaluate the performance impact of updating every time vs checking the set union
-        let chan = self.channels.entry(channel.into_owned()).or_default();
-        chan.members.clear();
-        // Alternative method -- Create set from input, insert all into members and retain.
-        // Other alternative method is to swap out the whole set... But I'm not so keen.
-        for v in members.into_owned().drain(..) {
-            chan.members.insert(v);
+        let members: Vec<CharacterId> = members.iter().map(|v| self.interner.intern(v)).collect();
+        let channel = channel.into_owned();
+        let chan = self.channels.entry(channel.clone()).or_default();
+        let filling = !chan.members_filled.swap(true, Ordering::Relaxed);
+        let (joined, left) = replace_set(&chan.members, members);
+        if joined.is_empty() && left.is_empty() {
+            return Ok(false);
+        }
+        chan.members_changed();
+        drop(chan);
+        if !filling {
+            for v in self.resolve_all::<Vec<Character>>(joined.into_iter()) {
+                self.record_member_change(&channel, v, true);
+            }
+            for v in self.resolve_all::<Vec<Character>>(left.into_iter()) {
+                self.record_member_change(&channel, v, false);
+            }
         }
         Ok(true)
     }
 
     fn set_global_channels(&self, channels: Cow<[(Channel, u32)]>) -> Result<bool, Self::Error> {
-        let mut changed = false;
-        for (channel, count) in channels.into_owned().drain(..) {
-            changed = self.global_channels.insert(channel, count).is_some() || changed;
-        }
-        Ok(changed)
+        let change = self.replace_channel_list(ChannelListKind::Official, channels.into_owned());
+        Ok(change.is_some())
     }
 
     fn set_unofficial_channels(
         &self,
         channels: Cow<[(Channel, u32)]>,
     ) -> Result<bool, Self::Error> {
-        // Caveat - I'm never cleaning up old channels. Bad idea, yes. Problem? Probably not, realistically - Low pop count.
-        let mut changed = false;
-        for (channel, count) in channels.into_owned().drain(..) {
-            changed = self.unofficial_channels.insert(channel, count).is_some() || changed;
+        let change =
+            match self.replace_channel_list(ChannelListKind::Unofficial, channels.into_owned()) {
+                Some(change) => change,
+                None => return Ok(false),
+            };
+        // Being listed is what makes a room public. One that drops off has been closed (or emptied).
+        for channel in change.added.keys() {
+            if let Some(mut chan) = self.channels.get_mut(channel) {
+                if chan.kind == ChannelKind::Private {
+                    chan.kind = ChannelKind::Public;
+                }
+            }
         }
-        Ok(changed)
+        for channel in change.removed.iter() {
+            if let Some(mut chan) = self.channels.get_mut(channel) {
+                if chan.kind == ChannelKind::Public {
+                    chan.kind = ChannelKind::Private;
+                }
+            }
+        }
+        Ok(true)
     }
 
     fn set_global_ops(&self, ops: Cow<[Character]>) -> Result<bool, Self::Error> {
-        self.admins.clear();
-        for admin in ops.into_owned().drain(..) {
-            self.admins.insert(admin);
-        }
-        Ok(true)
+        let ops: Vec<CharacterId> = ops.iter().map(|v| self.interner.intern(v)).collect();
+        let (added, removed) = replace_set(&self.admins, ops);
+        Ok(
+            self.record_list_diff(added, removed, |added, removed| ListChange::GlobalOps {
+                added,
+                removed,
+            }),
+        )
     }
 
     fn set_channel_ops(
@@ -304,42 +1797,41 @@ impl f_chat_rs::cache::Cache for Cache {
         channel: Cow<Channel>,
         ops: Cow<[Character]>,
     ) -> Result<bool, Self::Error> {
-        let chan = self.channels.entry(channel.into_owned()).or_default();
-        chan.ops.clear();
-        for op in ops.into_owned() {
-            chan.ops.insert(op);
+        let ops: Vec<CharacterId> = ops.iter().map(|v| self.interner.intern(v)).collect();
+        let channel = channel.into_owned();
+        let mut chan = self.channels.entry(channel.clone()).or_default();
+        let filling = !chan.ops_filled.swap(true, Ordering::Relaxed);
+        let owner = ops.first().cloned();
+        let owner_changed = std::mem::replace(&mut chan.owner, owner) != owner;
+        let (added, removed) = replace_set(&chan.ops, ops);
+        drop(chan);
+        if filling {
+            return Ok(owner_changed || !added.is_empty());
         }
-        Ok(true)
+        let changed =
+            self.record_list_diff(added, removed, |added, removed| ListChange::ChannelOps {
+                channel,
+                added,
+                removed,
+            });
+        Ok(changed || owner_changed)
     }
 
     fn get_channel(&self, channel: &Channel) -> Result<Option<ChannelData>, Self::Error> {
-        // Because we use CacheChannelData, we can't just return a copy of what's in the cache.
-        Ok(self.channels.get(channel).map(|v| ChannelData {
-            channel: channel.to_owned(),
-            channel_mode: v.mode,
-            members: v.members.iter().map(|v| v.to_owned()).collect(),
-            description: v.description.clone(),
-            title: v.title.clone(),
-        }))
+        // The trait wants it owned. Anything that can take the shared view should use channel_view.
+        Ok(self.channel_view(channel).map(|v| ChannelData::clone(&v)))
     }
 
     fn get_channels(&self) -> Result<Cow<[ChannelData]>, Self::Error> {
-        // Like get_channel, but even more expensive...
-        Ok(self
-            .channels
-            .iter()
-            .map(|v| ChannelData {
-                channel: v.key().to_owned(),
-                channel_mode: v.mode,
-                members: v.members.iter().map(|v| v.to_owned()).collect(),
-                description: v.description.clone(),
-                title: v.title.clone(),
-            })
-            .collect())
+        Ok(self.channel_views().iter().map(|v| (**v).clone()).collect())
     }
 
     fn get_character(&self, character: &Character) -> Result<Option<CharacterData>, Self::Error> {
-        Ok(self.characters.get(character).map(|v| CharacterData {
+        let id = match self.interner.id(character) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        Ok(self.characters.get(&id).map(|v| CharacterData {
             character: *character,
             gender: v.gender,
             status: v.status,
@@ -348,11 +1840,12 @@ impl f_chat_rs::cache::Cache for Cache {
     }
 
     fn get_characters(&self) -> Result<Cow<[CharacterData]>, Self::Error> {
+        let names = self.interner.names();
         Ok(self
             .characters
             .iter()
             .map(|v| CharacterData {
-                character: *v.key(),
+                character: names[v.key().0 as usize],
                 gender: v.gender,
                 status: v.status,
                 status_message: v.status_message.clone(),
@@ -372,23 +1865,33 @@ impl f_chat_rs::cache::Cache for Cache {
             .messages
             .get(source)
             .map(|messages| {
+                let limit: usize = limit.unwrap_or(80).try_into().unwrap();
+                let skip = messages.len().saturating_sub(limit);
+                let names = self.interner.names();
                 messages
                     .iter()
-                    .rev()
-                    .take(limit.unwrap_or(80).try_into().unwrap())
-                    .map(|v| v.clone())
-                    .rev()
+                    .skip(skip)
+                    .map(|v| v.clone().resolve(&names))
                     .collect()
-                // This is really stupid and I should just slice from the back and clone.
             })
             .map_or(Default::default(), |v| Cow::Owned(v)))
     }
 
     fn get_friend_relations(&self) -> Result<Cow<[FriendRelation]>, Self::Error> {
-        Ok(self.friends.iter().map(|v| v.clone()).collect())
+        let pairs: Vec<(CharacterId, CharacterId)> = self.friends.iter().map(|v| *v).collect();
+        let names = self.interner.names();
+        Ok(pairs
+            .into_iter()
+            .map(|(own, other)| FriendRelation {
+                own_character: names[own.0 as usize],
+                other_character: names[other.0 as usize],
+            })
+            .collect())
     }
 
     fn get_bookmarks(&self) -> Result<Cow<[Character]>, Self::Error> {
-        Ok(self.bookmarks.iter().map(|v| v.clone()).collect())
+        Ok(Cow::Owned(
+            self.resolve_all(self.bookmarks.iter().map(|v| *v)),
+        ))
     }
 }
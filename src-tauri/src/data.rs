@@ -1,5 +1,5 @@
-use f_chat_rs::data::{Gender, Status};
-use serde::Serialize;
+use f_chat_rs::data::{self as f_data, Channel, Character, Gender, Status};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 // For instances where there's data which needs to go to the frontend,
 // but for some reason I can't use one of the existing types (e.g. it's redundant or the wrong format)
@@ -10,3 +10,57 @@ pub struct CharacterDataInner {
     pub status: Status,
     pub status_message: String,
 }
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+// This is MessageChannel from f_chat_rs, but for a language without typed unions.
+// Maybe at a later time I'll go back to f_chat_rs and redesign how these are all described.
+pub enum MessageChannel {
+    Channel {
+        channel: Channel,
+    },
+    Character {
+        own_character: Character,
+        other_character: Character,
+    },
+}
+
+impl Into<f_data::MessageChannel> for MessageChannel {
+    fn into(self) -> f_data::MessageChannel {
+        match self {
+            MessageChannel::Channel { channel } => f_data::MessageChannel::Channel(channel),
+            MessageChannel::Character {
+                own_character,
+                other_character,
+            } => f_data::MessageChannel::PrivateMessage(own_character, other_character),
+        }
+    }
+}
+
+impl From<f_data::MessageChannel> for MessageChannel {
+    fn from(channel: f_data::MessageChannel) -> Self {
+        match channel {
+            f_data::MessageChannel::Channel(channel) => MessageChannel::Channel { channel },
+            f_data::MessageChannel::PrivateMessage(own_character, other_character) => {
+                MessageChannel::Character {
+                    own_character,
+                    other_character,
+                }
+            }
+        }
+    }
+}
+
+// Character and Channel don't promise any particular way to get at the name,
+// but they do serialize as one.
+pub fn name(value: &impl Serialize) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+// And the other way, for names that come from somewhere other than the server (e.g. old logs).
+pub fn from_name<T: DeserializeOwned>(name: &str) -> Option<T> {
+    serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+}
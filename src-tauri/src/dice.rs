@@ -0,0 +1,568 @@
+// Dice, as F-Chat's /roll understands them, and a record of what's been rolled where.
+// Local rolls take the same syntax and then some (keeping, dropping and exploding dice),
+// and go in the character's notes rather than out to the server.
+// Rooms that run games want to be able to look back over the rolls, so they're kept apart
+// from the cached messages and outlive anything evicted from them.
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+};
+
+use chrono::Utc;
+use dashmap::DashMap;
+use f_chat_rs::{
+    cache::Cache,
+    data::{Character, Message, MessageChannel, MessageContent},
+    util::Timestamp,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
+use tauri::{AppHandle, Manager, State};
+use thiserror::Error;
+use tokio::sync::mpsc::Sender;
+
+use crate::{
+    data,
+    event::{AccountEvent, UpdateEvent},
+    logs::{load_log, LogEntry, Logs},
+    store::Store,
+    MaybeClient,
+};
+
+// What the server accepts. It refuses anything outside these, so there's no point sending it.
+const MAX_TERMS: usize = 20;
+const MAX_DICE: u32 = 9;
+const MIN_SIDES: u32 = 2;
+const MAX_SIDES: u32 = 500;
+const MAX_MODIFIER: i64 = 10000;
+// Local rolls only have to stay sensible.
+const LOCAL_MAX_DICE: u32 = 100;
+const LOCAL_MAX_SIDES: u32 = 1000;
+// Per die. A d2 that keeps coming up 2 has to stop somewhere.
+pub const MAX_EXPLOSIONS: u32 = 100;
+// Per conversation.
+const ROLL_HISTORY_LIMIT: usize = 1000;
+
+#[derive(Error, Debug, Serialize)]
+pub enum DiceError {
+    #[error("Nothing to roll")]
+    Empty,
+    #[error("At most {} dice and modifiers at once", MAX_TERMS)]
+    TooManyTerms,
+    #[error("Between 1 and {1} dice at a time, not {0}")]
+    BadCount(u32, u32),
+    #[error("Dice have between {} and {1} sides, not {0}", MIN_SIDES)]
+    BadSides(u32, u32),
+    #[error("Can't keep or drop {0} of {1} dice")]
+    BadKeep(u32, u32),
+    #[error("Keeping, dropping and exploding dice only work for local rolls")]
+    LocalOnly,
+    #[error("Modifiers go up to {}, not {0}", MAX_MODIFIER)]
+    BadModifier(i64),
+    #[error("Can't make sense of \"{0}\"")]
+    Invalid(String),
+    #[error("Not logged in")]
+    NoClient,
+    #[error("Client error: {0}")]
+    Client(String),
+    #[error("Couldn't write to notes: {0}")]
+    Notes(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+    Highest(u32),
+    Lowest(u32),
+    DropHighest(u32),
+    DropLowest(u32),
+}
+
+impl Keep {
+    // How many are kept, and whether they're the highest.
+    fn kept(&self, count: u32) -> (u32, bool) {
+        match *self {
+            Keep::Highest(n) => (n, true),
+            Keep::Lowest(n) => (n, false),
+            Keep::DropHighest(n) => (count.saturating_sub(n), false),
+            Keep::DropLowest(n) => (count.saturating_sub(n), true),
+        }
+    }
+
+    fn amount(&self) -> u32 {
+        match *self {
+            Keep::Highest(n) | Keep::Lowest(n) | Keep::DropHighest(n) | Keep::DropLowest(n) => n,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiceTerm {
+    pub count: u32,
+    pub sides: u32,
+    // A die that comes up on its highest side is rolled again and added on ("1d6!").
+    pub explode: bool,
+    // "4d6kh3", "4d6dl1" and so on. A bare "k" is keep highest.
+    pub keep: Option<Keep>,
+}
+
+impl fmt::Display for DiceTerm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}d{}", self.count, self.sides)?;
+        if self.explode {
+            write!(f, "!")?;
+        }
+        match self.keep {
+            Some(Keep::Highest(n)) => write!(f, "kh{}", n),
+            Some(Keep::Lowest(n)) => write!(f, "kl{}", n),
+            Some(Keep::DropHighest(n)) => write!(f, "dh{}", n),
+            Some(Keep::DropLowest(n)) => write!(f, "dl{}", n),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TermKind {
+    Dice(DiceTerm),
+    Modifier(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Term {
+    pub negative: bool,
+    pub kind: TermKind,
+}
+
+fn parse_keep(text: &str) -> Option<Keep> {
+    let (kind, amount): (fn(u32) -> Keep, &str) = if let Some(v) = text.strip_prefix("kh") {
+        (Keep::Highest, v)
+    } else if let Some(v) = text.strip_prefix("kl") {
+        (Keep::Lowest, v)
+    } else if let Some(v) = text.strip_prefix("dh") {
+        (Keep::DropHighest, v)
+    } else if let Some(v) = text.strip_prefix("dl") {
+        (Keep::DropLowest, v)
+    } else if let Some(v) = text.strip_prefix('k') {
+        (Keep::Highest, v)
+    } else {
+        return None;
+    };
+    amount.parse().ok().map(kind)
+}
+
+fn parse_term(text: &str) -> Result<TermKind, DiceError> {
+    let invalid = || DiceError::Invalid(text.to_string());
+    let (count, rest) = match text.split_once('d') {
+        Some(v) => v,
+        None => return Ok(TermKind::Modifier(text.parse().map_err(|_| invalid())?)),
+    };
+    let sides_end = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    let (sides, mut suffix) = rest.split_at(sides_end);
+    let explode = match suffix.strip_prefix('!') {
+        Some(v) => {
+            suffix = v;
+            true
+        }
+        None => false,
+    };
+    let keep = match suffix {
+        "" => None,
+        suffix => Some(parse_keep(suffix).ok_or_else(invalid)?),
+    };
+    Ok(TermKind::Dice(DiceTerm {
+        count: count.parse().map_err(|_| invalid())?,
+        sides: sides.parse().map_err(|_| invalid())?,
+        explode,
+        keep,
+    }))
+}
+
+// "2d6+1d4-2", with any spacing and case. Limits aren't checked here; see validate.
+pub fn parse(text: &str) -> Result<Vec<Term>, DiceError> {
+    let text: String = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    if text.is_empty() {
+        return Err(DiceError::Empty);
+    }
+
+    let mut terms = Vec::new();
+    let (mut negative, mut rest) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text.as_str()),
+    };
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        terms.push(Term {
+            negative,
+            kind: parse_term(&rest[..end])?,
+        });
+        if end == rest.len() {
+            return Ok(terms);
+        }
+        negative = rest[end..].starts_with('-');
+        rest = &rest[end + 1..];
+    }
+}
+
+// Checks an expression against what the server will take, so a bad one fails here with a reason
+// instead of going out and coming back as an error message.
+pub fn validate(text: &str) -> Result<(), DiceError> {
+    if text.trim().eq_ignore_ascii_case("bottle") {
+        return Ok(());
+    }
+    let terms = parse(text)?;
+    if terms.len() > MAX_TERMS {
+        return Err(DiceError::TooManyTerms);
+    }
+    for term in terms.iter() {
+        if let TermKind::Dice(die) = term.kind {
+            if die.explode || die.keep.is_some() {
+                return Err(DiceError::LocalOnly);
+            }
+        }
+    }
+    check_limits(&terms, MAX_DICE, MAX_SIDES)
+}
+
+pub fn check_limits(terms: &[Term], max_dice: u32, max_sides: u32) -> Result<(), DiceError> {
+    for term in terms {
+        match term.kind {
+            TermKind::Dice(DiceTerm { count, .. }) if !(1..=max_dice).contains(&count) => {
+                return Err(DiceError::BadCount(count, max_dice))
+            }
+            TermKind::Dice(DiceTerm { sides, .. }) if !(MIN_SIDES..=max_sides).contains(&sides) => {
+                return Err(DiceError::BadSides(sides, max_sides))
+            }
+            TermKind::Dice(DiceTerm {
+                count,
+                keep: Some(keep),
+                ..
+            }) if keep.amount() > count => return Err(DiceError::BadKeep(keep.amount(), count)),
+            TermKind::Modifier(value) if value.abs() > MAX_MODIFIER => {
+                return Err(DiceError::BadModifier(value))
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TermRoll {
+    pub term: String,
+    // Every die in the order they came up, with any explosions added in.
+    pub dice: Vec<i32>,
+    // Which of those count towards the result.
+    pub kept: Vec<bool>,
+    pub result: i32,
+}
+
+fn roll_dice(die: &DiceTerm, rng: &mut impl Rng) -> (Vec<i32>, Vec<bool>) {
+    // An exploding die's rolls are added together, so it's still one die as far as keeping goes.
+    let dice: Vec<i32> = (0..die.count)
+        .map(|_| {
+            let mut total = 0;
+            let mut explosions = 0;
+            loop {
+                let value = rng.gen_range(1..=die.sides);
+                total += value as i32;
+                if !die.explode || value < die.sides || explosions >= MAX_EXPLOSIONS {
+                    return total;
+                }
+                explosions += 1;
+            }
+        })
+        .collect();
+
+    let mut kept = vec![true; dice.len()];
+    if let Some(keep) = die.keep {
+        let (keeping, highest) = keep.kept(die.count);
+        // Worst first, so whatever's dropped comes off the front.
+        let mut order: Vec<usize> = (0..dice.len()).collect();
+        order.sort_by_key(|&i| if highest { dice[i] } else { -dice[i] });
+        for i in order.into_iter().take(dice.len() - keeping as usize) {
+            kept[i] = false;
+        }
+    }
+    (dice, kept)
+}
+
+// Each term's result carries its sign, the way the server reports them.
+pub fn roll(terms: &[Term], rng: &mut impl Rng) -> Vec<TermRoll> {
+    terms
+        .iter()
+        .map(|term| {
+            let sign = if term.negative { -1 } else { 1 };
+            let prefix = if term.negative { "-" } else { "" };
+            match term.kind {
+                TermKind::Dice(die) => {
+                    let (dice, kept) = roll_dice(&die, rng);
+                    let result: i32 = dice
+                        .iter()
+                        .zip(kept.iter())
+                        .filter(|v| *v.1)
+                        .map(|v| *v.0)
+                        .sum();
+                    TermRoll {
+                        term: format!("{}{}", prefix, die),
+                        dice,
+                        kept,
+                        result: sign * result,
+                    }
+                }
+                TermKind::Modifier(value) => TermRoll {
+                    term: format!("{}{}", prefix, value),
+                    dice: Vec::new(),
+                    kept: Vec::new(),
+                    result: sign * value as i32,
+                },
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RollResult {
+    // As the server reports it: each term as written, what each came to, and the total.
+    Dice {
+        dice: Vec<String>,
+        results: Vec<i32>,
+        total: i32,
+    },
+    Bottle {
+        target: Character,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RollRecord {
+    pub at: Timestamp,
+    pub character: Character,
+    #[serde(flatten)]
+    pub result: RollResult,
+}
+
+#[derive(Debug, Default)]
+pub struct RollHistory {
+    rolls: DashMap<MessageChannel, VecDeque<RollRecord>>,
+}
+
+impl RollHistory {
+    // Anything that isn't a roll or a bottle is ignored, so every message can be passed in.
+    pub fn record(&self, channel: &MessageChannel, message: &Message) {
+        let result = match &message.content {
+            MessageContent::Roll(dice, results, total) => RollResult::Dice {
+                dice: dice.clone(),
+                results: results.clone(),
+                total: *total,
+            },
+            MessageContent::Bottle(target) => RollResult::Bottle { target: *target },
+            _ => return,
+        };
+        let mut rolls = self.rolls.entry(channel.clone()).or_default();
+        rolls.push_back(RollRecord {
+            at: message.timestamp,
+            character: message.character,
+            result,
+        });
+        while rolls.len() > ROLL_HISTORY_LIMIT {
+            rolls.pop_front();
+        }
+    }
+
+    pub fn stats(&self, channel: &MessageChannel, since: Option<Timestamp>) -> RollStats {
+        let rolls: Vec<RollRecord> = self.rolls.get(channel).map_or(Vec::new(), |v| {
+            v.iter()
+                .filter(|v| since.map_or(true, |since| v.at >= since))
+                .cloned()
+                .collect()
+        });
+        RollStats::from_records(rolls)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Tally {
+    pub rolls: usize,
+    pub mean: f64,
+    pub lowest: i32,
+    pub highest: i32,
+}
+
+impl Tally {
+    fn add(&mut self, value: i32) {
+        if self.rolls == 0 {
+            self.lowest = value;
+            self.highest = value;
+        } else {
+            self.lowest = self.lowest.min(value);
+            self.highest = self.highest.max(value);
+        }
+        self.rolls += 1;
+        self.mean += (value as f64 - self.mean) / self.rolls as f64;
+    }
+}
+
+// For each kind of die, how it's been landing against how it should.
+// A mean that's drifted well away from the expected over many rolls is worth a look.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DiceStats {
+    #[serde(flatten)]
+    pub tally: Tally,
+    pub expected: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RollStats {
+    pub rolls: usize,
+    // Totals, per character who rolled.
+    pub characters: HashMap<Character, Tally>,
+    // Keyed by the term as written, e.g. "2d6".
+    pub dice: BTreeMap<String, DiceStats>,
+    // Who the bottle landed on, and how often.
+    pub bottles: HashMap<Character, usize>,
+    pub history: Vec<RollRecord>,
+}
+
+impl RollStats {
+    fn from_records(history: Vec<RollRecord>) -> Self {
+        let mut stats = RollStats::default();
+        for record in history.iter() {
+            match &record.result {
+                RollResult::Dice {
+                    dice,
+                    results,
+                    total,
+                } => {
+                    stats.rolls += 1;
+                    stats
+                        .characters
+                        .entry(record.character)
+                        .or_default()
+                        .add(*total);
+                    // Modifiers are reported as terms too, and have nothing to compare against.
+                    for (term, result) in dice.iter().zip(results.iter()) {
+                        let term = term.trim_start_matches(['+', '-']).to_lowercase();
+                        if let Ok(TermKind::Dice(die)) = parse_term(&term) {
+                            let v = stats.dice.entry(term).or_default();
+                            v.expected = die.count as f64 * (die.sides as f64 + 1.0) / 2.0;
+                            v.tally.add(result.abs());
+                        }
+                    }
+                }
+                RollResult::Bottle { target } => {
+                    *stats.bottles.entry(*target).or_default() += 1;
+                }
+            }
+        }
+        stats.history = history;
+        stats
+    }
+}
+
+#[tauri::command]
+pub async fn get_roll_stats(
+    client: State<'_, MaybeClient>,
+    channel: data::MessageChannel,
+    account: Option<String>,
+    since: Option<Timestamp>,
+) -> Result<RollStats, DiceError> {
+    let client = crate::for_message_channel(&client, &channel, account.as_deref())
+        .await
+        .ok_or(DiceError::NoClient)?;
+    Ok(client.cache.roll_stats(&channel.into(), since))
+}
+
+// A character's own notes: a conversation with themselves, which nothing from the server can end up in.
+pub fn notes_channel(character: Character) -> MessageChannel {
+    MessageChannel::PrivateMessage(character, character)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LocalRoll {
+    // Rolling the same expression with this seed again gives the same dice.
+    // (For as long as rand's StdRng stays the same, which is at least until rand is upgraded.)
+    pub seed: u64,
+    pub terms: Vec<TermRoll>,
+    pub total: i32,
+}
+
+// Rolled here rather than by the server, and written to the character's notes instead of a room.
+// Nobody has to be logged in: the notes go straight to the logs.
+#[tauri::command]
+pub async fn roll_local(
+    app: AppHandle,
+    client: State<'_, MaybeClient>,
+    emitter: State<'_, Sender<AccountEvent>>,
+    character: Character,
+    expression: String,
+    seed: Option<u64>,
+) -> Result<LocalRoll, DiceError> {
+    let terms = parse(&expression)?;
+    if terms.len() > MAX_TERMS {
+        return Err(DiceError::TooManyTerms);
+    }
+    check_limits(&terms, LOCAL_MAX_DICE, LOCAL_MAX_SIDES)?;
+
+    // Kept to 53 bits, so it survives a round trip through a JavaScript number.
+    let seed = seed.unwrap_or_else(|| rand::random::<u64>() >> 11);
+    let terms = roll(&terms, &mut StdRng::seed_from_u64(seed));
+    let total = terms.iter().map(|v| v.result).sum();
+    let channel = notes_channel(character);
+    let message = Message {
+        timestamp: Utc::now(),
+        character,
+        content: MessageContent::Roll(
+            terms.iter().map(|v| v.term.clone()).collect(),
+            terms.iter().map(|v| v.result).collect(),
+            total,
+        ),
+    };
+
+    // Not through the cache, or it'd be logged a second time when it's evicted.
+    let entry = LogEntry::from(message.clone());
+    let log_channel = channel.clone();
+    let log_app = app.clone();
+    tokio::task::spawn_blocking(move || {
+        log_app
+            .state::<Logs>()
+            .append(&log_app.state::<Store>(), &log_channel, vec![entry])
+    })
+    .await
+    .map_err(|err| DiceError::Notes(err.to_string()))?
+    .map_err(|err| DiceError::Notes(err.to_string()))?;
+
+    // If the character's logged in, it's shown like any other message.
+    let account = client
+        .all()
+        .await
+        .into_iter()
+        .find(|v| v.1.own_characters.contains(&character))
+        .map(|v| v.0);
+    if let Some(account) = account {
+        let event = AccountEvent {
+            account,
+            event: UpdateEvent::Message(channel, message),
+        };
+        if let Err(err) = emitter.send(event).await {
+            eprintln!("Dropped local roll, event loop is gone: {:?}", err.0);
+        }
+    }
+    Ok(LocalRoll { seed, terms, total })
+}
+
+// Everything in a character's notes since the given time, whether or not they're logged in.
+#[tauri::command]
+pub async fn get_notes(
+    store: State<'_, Store>,
+    character: Character,
+    since: Option<Timestamp>,
+) -> Result<Vec<LogEntry>, ()> {
+    Ok(load_log(&store, &notes_channel(character), since, None))
+}
@@ -0,0 +1,183 @@
+// The roller on its own: parsing, limits and rolling, with no client or server involved.
+use rand::{rngs::StdRng, SeedableRng};
+
+use crate::dice::{
+    check_limits, parse, roll, validate, DiceError, DiceTerm, Keep, Term, TermKind, MAX_EXPLOSIONS,
+};
+
+fn dice(count: u32, sides: u32) -> DiceTerm {
+    DiceTerm {
+        count,
+        sides,
+        explode: false,
+        keep: None,
+    }
+}
+
+fn term(negative: bool, kind: TermKind) -> Term {
+    Term { negative, kind }
+}
+
+#[test]
+fn parses_dice_and_modifiers() {
+    let terms = parse("2d6 + 1D4 - 2").unwrap();
+    assert_eq!(
+        terms,
+        vec![
+            term(false, TermKind::Dice(dice(2, 6))),
+            term(false, TermKind::Dice(dice(1, 4))),
+            term(true, TermKind::Modifier(2)),
+        ]
+    );
+}
+
+#[test]
+fn parses_a_leading_minus() {
+    let terms = parse("-1d8").unwrap();
+    assert_eq!(terms, vec![term(true, TermKind::Dice(dice(1, 8)))]);
+}
+
+#[test]
+fn parses_keeping_dropping_and_exploding() {
+    let kinds: Vec<TermKind> = parse("4d6kh3+4d6dl1+2d20k1+1d6!+3d10!kl2")
+        .unwrap()
+        .into_iter()
+        .map(|v| v.kind)
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            TermKind::Dice(DiceTerm {
+                keep: Some(Keep::Highest(3)),
+                ..dice(4, 6)
+            }),
+            TermKind::Dice(DiceTerm {
+                keep: Some(Keep::DropLowest(1)),
+                ..dice(4, 6)
+            }),
+            TermKind::Dice(DiceTerm {
+                keep: Some(Keep::Highest(1)),
+                ..dice(2, 20)
+            }),
+            TermKind::Dice(DiceTerm {
+                explode: true,
+                ..dice(1, 6)
+            }),
+            TermKind::Dice(DiceTerm {
+                explode: true,
+                keep: Some(Keep::Lowest(2)),
+                ..dice(3, 10)
+            }),
+        ]
+    );
+}
+
+#[test]
+fn parse_rejects_nonsense() {
+    assert!(matches!(parse("  "), Err(DiceError::Empty)));
+    assert!(matches!(parse("2d"), Err(DiceError::Invalid(_))));
+    assert!(matches!(parse("d6"), Err(DiceError::Invalid(_))));
+    assert!(matches!(parse("2d6+"), Err(DiceError::Invalid(_))));
+    assert!(matches!(parse("2d6kx"), Err(DiceError::Invalid(_))));
+    assert!(matches!(parse("fireball"), Err(DiceError::Invalid(_))));
+}
+
+#[test]
+fn keeping_more_than_was_rolled_is_refused() {
+    let terms = parse("2d6kh3").unwrap();
+    assert!(matches!(
+        check_limits(&terms, 100, 1000),
+        Err(DiceError::BadKeep(3, 2))
+    ));
+    let terms = parse("2d6dl3").unwrap();
+    assert!(matches!(
+        check_limits(&terms, 100, 1000),
+        Err(DiceError::BadKeep(3, 2))
+    ));
+    let terms = parse("2d6kh2").unwrap();
+    assert!(check_limits(&terms, 100, 1000).is_ok());
+}
+
+#[test]
+fn limits_are_checked() {
+    let check = |text: &str| check_limits(&parse(text).unwrap(), 10, 100);
+    assert!(matches!(check("0d6"), Err(DiceError::BadCount(0, 10))));
+    assert!(matches!(check("11d6"), Err(DiceError::BadCount(11, 10))));
+    assert!(matches!(check("1d1"), Err(DiceError::BadSides(1, 100))));
+    assert!(matches!(check("1d101"), Err(DiceError::BadSides(101, 100))));
+    assert!(matches!(
+        check("1d6+10001"),
+        Err(DiceError::BadModifier(10001))
+    ));
+    assert!(check("10d100-10000").is_ok());
+}
+
+#[test]
+fn the_server_only_takes_plain_dice() {
+    assert!(validate("bottle").is_ok());
+    assert!(validate("9d500+1d2").is_ok());
+    assert!(matches!(validate("10d6"), Err(DiceError::BadCount(10, 9))));
+    assert!(matches!(validate("4d6kh3"), Err(DiceError::LocalOnly)));
+    assert!(matches!(validate("1d6!"), Err(DiceError::LocalOnly)));
+}
+
+#[test]
+fn explosions_stop_at_the_cap() {
+    // A d2 explodes half the time, so across this many dice some chains run long, but none
+    // can run past the cap.
+    let terms = parse("100d2!").unwrap();
+    let most = 2 * (MAX_EXPLOSIONS as i32 + 1);
+    let mut exploded = false;
+    for seed in 0..100 {
+        let rolled = roll(&terms, &mut StdRng::seed_from_u64(seed));
+        for die in rolled[0].dice.iter() {
+            assert!(*die >= 1 && *die <= most, "{} is past the cap", die);
+            exploded |= *die > 2;
+        }
+    }
+    assert!(exploded);
+}
+
+#[test]
+fn kept_dice_make_up_the_result() {
+    let terms = parse("4d6dl1-2").unwrap();
+    for seed in 0..100 {
+        let rolled = roll(&terms, &mut StdRng::seed_from_u64(seed));
+        let die = &rolled[0];
+        assert_eq!(die.kept.iter().filter(|v| **v).count(), 3);
+        let lowest = *die.dice.iter().min().unwrap();
+        let kept: i32 = die
+            .dice
+            .iter()
+            .zip(die.kept.iter())
+            .filter(|v| *v.1)
+            .map(|v| *v.0)
+            .sum();
+        assert_eq!(kept, die.dice.iter().sum::<i32>() - lowest);
+        assert_eq!(die.result, kept);
+        assert_eq!(rolled[1].result, -2);
+    }
+}
+
+#[test]
+fn the_same_seed_rolls_the_same_dice() {
+    let terms = parse("4d6kh3+2d20!-1d8+5").unwrap();
+    let first = roll(&terms, &mut StdRng::seed_from_u64(1234));
+    let second = roll(&terms, &mut StdRng::seed_from_u64(1234));
+    assert_eq!(first.len(), second.len());
+    for (a, b) in first.iter().zip(second.iter()) {
+        assert_eq!(a.term, b.term);
+        assert_eq!(a.dice, b.dice);
+        assert_eq!(a.kept, b.kept);
+        assert_eq!(a.result, b.result);
+    }
+
+    // And a different one doesn't, at least not every time.
+    let differs = (0..10).any(|seed| {
+        roll(&terms, &mut StdRng::seed_from_u64(seed))
+            .iter()
+            .zip(first.iter())
+            .any(|(a, b)| a.dice != b.dice)
+    });
+    assert!(differs);
+}
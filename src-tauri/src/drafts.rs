@@ -0,0 +1,132 @@
+// Whatever's in the editor, kept on disk so a route change or a crash doesn't take a half-written post with it.
+// One file per character and conversation under drafts/, since two characters in the same room write different posts.
+use std::{collections::VecDeque, sync::Mutex};
+
+use chrono::{Duration, Utc};
+use f_chat_rs::{
+    data::{Character, MessageChannel},
+    util::Timestamp,
+};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+use crate::{
+    data,
+    logs::encode_name,
+    store::{Store, StoreError},
+};
+
+const DRAFT_DIR: &str = "drafts";
+const DRAFT_HISTORY_LIMIT: usize = 20;
+// The editor saves every few seconds while typing. Keeping each of those as a version would
+// push anything worth going back to out of the history within a minute.
+const VERSION_INTERVAL_SECS: i64 = 120;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Draft {
+    pub text: String,
+    pub saved: Option<Timestamp>,
+    // Newest first
+    pub history: VecDeque<DraftVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DraftVersion {
+    pub text: String,
+    pub saved: Timestamp,
+}
+
+impl Draft {
+    // Returns false if there was nothing to change.
+    fn update(&mut self, text: String, now: Timestamp) -> bool {
+        if text == self.text {
+            return false;
+        }
+        // Clearing the editor (usually by sending) always keeps what was there, otherwise only every so often.
+        let due = self.history.front().map_or(true, |last| {
+            now - last.saved >= Duration::seconds(VERSION_INTERVAL_SECS)
+        });
+        let repeated = self
+            .history
+            .front()
+            .map_or(false, |last| last.text == self.text);
+        if !self.text.is_empty() && !repeated && (due || text.is_empty()) {
+            self.history.push_front(DraftVersion {
+                text: std::mem::take(&mut self.text),
+                saved: self.saved.unwrap_or(now),
+            });
+            self.history.truncate(DRAFT_HISTORY_LIMIT);
+        }
+        self.text = text;
+        self.saved = Some(now);
+        true
+    }
+}
+
+// Saves for the same draft can overlap, and each one is a load and a write of the whole file.
+#[derive(Debug, Default)]
+pub struct Drafts {
+    writing: Mutex<()>,
+}
+
+impl Drafts {
+    pub fn new() -> Self {
+        Drafts::default()
+    }
+
+    pub fn save(
+        &self,
+        store: &Store,
+        character: &Character,
+        channel: &MessageChannel,
+        text: String,
+    ) -> Result<Draft, StoreError> {
+        let _writing = self.writing.lock().unwrap();
+        let name = draft_name(character, channel);
+        // A draft that can't be read is left alone rather than replaced, history and all.
+        let mut draft: Draft = store.load_existing(&name)?;
+        if draft.update(text, Utc::now()) {
+            store.save(&name, &draft)?;
+        }
+        Ok(draft)
+    }
+}
+
+// Encoded the same way as log names, so "Foo Bar" and "Foo_Bar" don't share a draft.
+pub fn draft_name(character: &Character, channel: &MessageChannel) -> String {
+    match channel {
+        MessageChannel::Channel(channel) => format!(
+            "{}/{}-channel-{}.json",
+            DRAFT_DIR,
+            encode_name(&data::name(character)),
+            encode_name(&data::name(channel))
+        ),
+        // The own character is already part of a private conversation
+        MessageChannel::PrivateMessage(own, other) => format!(
+            "{}/{}-pm-{}.json",
+            DRAFT_DIR,
+            encode_name(&data::name(own)),
+            encode_name(&data::name(other))
+        ),
+    }
+}
+
+#[tauri::command]
+pub async fn save_draft(
+    drafts: State<'_, Drafts>,
+    store: State<'_, Store>,
+    character: Character,
+    channel: data::MessageChannel,
+    text: String,
+) -> Result<Draft, StoreError> {
+    drafts.save(&store, &character, &channel.into(), text)
+}
+
+#[tauri::command]
+pub async fn get_draft(
+    store: State<'_, Store>,
+    character: Character,
+    channel: data::MessageChannel,
+) -> Result<Draft, ()> {
+    Ok(store.load(&draft_name(&character, &channel.into())))
+}
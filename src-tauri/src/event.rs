@@ -1,26 +1,67 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
 use chrono::Utc;
-use tokio::sync::mpsc::Sender;
-
 use f_chat_rs::{
+    cache::Cache,
     client::{async_trait, EventListener},
-    data::{Channel, Character, Message, MessageChannel, MessageContent},
+    data::{Channel, ChannelData, Character, Message, MessageChannel, MessageContent},
     session::Session,
 };
 use serde::Serialize;
-use tauri::{Manager, Runtime};
+use tauri::{AppHandle, Manager, Runtime};
+use tokio::{
+    sync::mpsc::{Receiver, Sender},
+    time::{timeout, timeout_at, Instant},
+};
+
+use crate::{
+    cache::{ChannelMember, PresenceKind, UnreadData},
+    data,
+    logs::{LogEntry, Logs},
+    settings::SettingsStore,
+    store::Store,
+    watch::Watch,
+    MaybeClient,
+};
+
+// Big enough to soak up a login's worth of status updates without making the client wait.
+pub const EVENT_BUFFER: usize = 1024;
+const BATCH_WINDOW: Duration = Duration::from_millis(50);
+// How long the loop waits for an event before flushing the cache's queues anyway.
+const QUEUE_TICK: Duration = Duration::from_secs(1);
 
 #[derive(Debug)]
 pub struct EventHandler {
-    update_emitter: Sender<UpdateEvent>,
+    account: String,
+    update_emitter: Sender<AccountEvent>,
 }
 impl EventHandler {
-    pub fn new(update_emitter: Sender<UpdateEvent>) -> Self {
-        EventHandler { update_emitter }
+    pub fn new(account: String, update_emitter: Sender<AccountEvent>) -> Self {
+        EventHandler {
+            account,
+            update_emitter,
+        }
     }
 }
 
+// Every account's client feeds the same event loop, so each event says where it came from.
+#[derive(Debug)]
+pub struct AccountEvent {
+    pub account: String,
+    pub event: UpdateEvent,
+}
+
+// What the frontend gets: the payload, and which account it's about.
+#[derive(Debug, Serialize, Clone)]
+struct ForAccount<'a, T> {
+    account: &'a str,
+    data: T,
+}
+
 #[derive(Debug)]
 pub enum UpdateEvent {
     Bookmarks,
@@ -29,6 +70,15 @@ pub enum UpdateEvent {
     Friends,
 
     Message(MessageChannel, Message),
+    Invite(EventInvite),
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EventInvite {
+    pub session: Character,
+    pub sender: Character,
+    pub channel: Channel,
+    pub title: String,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -37,6 +87,20 @@ pub struct EventMessage {
     message: Message,
 }
 
+impl EventHandler {
+    // A full buffer means waiting (backpressure on the connection), a closed one means we're
+    // shutting down. Neither is worth taking the client down over.
+    async fn emit(&self, event: UpdateEvent) {
+        let event = AccountEvent {
+            account: self.account.clone(),
+            event,
+        };
+        if let Err(err) = self.update_emitter.send(event).await {
+            eprintln!("Dropped update, event loop is gone: {:?}", err.0);
+        }
+    }
+}
+
 #[async_trait]
 impl EventListener for EventHandler {
     async fn message(
@@ -46,43 +110,51 @@ impl EventListener for EventHandler {
         character: Character,
         content: MessageContent,
     ) {
-        self.update_emitter
-            .send(UpdateEvent::Message(
-                channel,
-                Message {
-                    character,
-                    content,
-                    timestamp: Utc::now(),
-                },
-            ))
-            .await
-            .expect("Event failed (message)");
+        self.emit(UpdateEvent::Message(
+            channel,
+            Message {
+                character,
+                content,
+                timestamp: Utc::now(),
+            },
+        ))
+        .await;
     }
 
     async fn updated_friends(&self) {
-        self.update_emitter
-            .send(UpdateEvent::Friends)
-            .await
-            .expect("Event failed (updated_friends)");
+        self.emit(UpdateEvent::Friends).await;
     }
     async fn updated_bookmarks(&self) {
-        self.update_emitter
-            .send(UpdateEvent::Bookmarks)
-            .await
-            .expect("Event failed (updated_bookmarks)");
+        self.emit(UpdateEvent::Bookmarks).await;
     }
     async fn updated_channel(&self, channel: Channel) {
-        self.update_emitter
-            .send(UpdateEvent::Channel(channel))
-            .await
-            .expect("Event failed (updated_channel)");
+        self.emit(UpdateEvent::Channel(channel)).await;
     }
     async fn updated_character(&self, user: Character) {
-        self.update_emitter
-            .send(UpdateEvent::Character(user))
-            .await
-            .expect("Event failed (updated_character)");
+        self.emit(UpdateEvent::Character(user)).await;
     }
+
+    async fn invited(&self, ctx: Arc<Session>, sender: Character, channel: Channel, title: String) {
+        self.emit(UpdateEvent::Invite(EventInvite {
+            session: ctx.character,
+            sender,
+            channel,
+            title,
+        }))
+        .await;
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EventMemberJoined {
+    channel: Channel,
+    member: ChannelMember,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct EventMemberLeft {
+    channel: Channel,
+    character: Character,
 }
 
 #[derive(Serialize, Clone, Debug)]
@@ -93,15 +165,333 @@ pub struct MessageEvent {
     content: String,
 }
 
-pub async fn handle_event<R: Runtime>(handle: &impl Manager<R>, event: UpdateEvent) {
+#[derive(Serialize, Clone, Debug)]
+pub struct UnreadEvent {
+    pub account: String,
+    pub channel: data::MessageChannel,
+    #[serde(flatten)]
+    pub data: UnreadData,
+}
+
+// Own messages count as reading the channel, anything else is unread until marked otherwise.
+// PMs are always a mention; in channels, it's any own character's name (or highlight word) in the text.
+async fn update_unread<R: Runtime>(
+    handle: &impl Manager<R>,
+    account: &str,
+    channel: &MessageChannel,
+    message: &Message,
+) -> Option<UnreadEvent> {
+    // A character's notes (local rolls and the like) are a conversation with themselves, and
+    // there's no session needed to write them, so they'd otherwise look like a PM from someone else.
+    if let MessageChannel::PrivateMessage(own, other) = channel {
+        if own == other {
+            return None;
+        }
+    }
+    let client = handle.state::<MaybeClient>().get(Some(account)).await?;
+
+    let own_characters: Vec<Character> = client
+        .get_sessions()
+        .drain(..)
+        .map(|v| v.character.clone())
+        .collect();
+    let data = if own_characters.contains(&message.character) {
+        let data = client
+            .cache
+            .mark_read(channel.clone(), Some(message.timestamp));
+        // Saved the same as marking read by hand, or it'd be unread again after a restart.
+        let markers = client.cache.read_markers();
+        let app = handle.app_handle();
+        tokio::task::spawn_blocking(move || {
+            let markers = markers
+                .into_iter()
+                .map(|(channel, at)| (channel.into(), at));
+            if let Err(err) = app.state::<Store>().save_read_markers(markers) {
+                eprintln!("Failed to save read markers: {}", err);
+            }
+        });
+        data
+    } else {
+        let mention = match channel {
+            MessageChannel::PrivateMessage(..) => true,
+            MessageChannel::Channel(_) => match &message.content {
+                MessageContent::Message(text) | MessageContent::Emote(text) => {
+                    let text = text.to_lowercase();
+                    let settings = handle.state::<SettingsStore>();
+                    own_characters.iter().any(|v| {
+                        mentions(&text, &data::name(v).to_lowercase())
+                            || settings
+                                .character(v)
+                                .highlight_words
+                                .iter()
+                                .any(|word| mentions(&text, &word.to_lowercase()))
+                    })
+                }
+                _ => false,
+            },
+        };
+        client.cache.record_unread(channel, message, mention)
+    };
+    Some(UnreadEvent {
+        account: account.to_string(),
+        channel: channel.clone().into(),
+        data,
+    })
+}
+
+// Whole words only, or "Al" would be mentioned by everyone saying "also".
+fn mentions(text: &str, word: &str) -> bool {
+    if word.is_empty() {
+        return false;
+    }
+    text.match_indices(word).any(|(start, _)| {
+        let end = start + word.len();
+        !text[..start]
+            .chars()
+            .next_back()
+            .map_or(false, char::is_alphanumeric)
+            && !text[end..]
+                .chars()
+                .next()
+                .map_or(false, char::is_alphanumeric)
+    })
+}
+
+// Messages pushed out of the cache go to the logs, a conversation at a time.
+// That's disk, so it happens off to the side rather than holding up the events behind it.
+pub async fn log_evicted<R: Runtime>(handle: &impl Manager<R>, account: &str) {
+    let client = match handle.state::<MaybeClient>().get(Some(account)).await {
+        Some(client) => client,
+        None => return,
+    };
+    let evicted = client.cache.take_evicted();
+    if evicted.is_empty() {
+        return;
+    }
+    let mut conversations: HashMap<MessageChannel, Vec<LogEntry>> = HashMap::new();
+    for (channel, entry) in evicted {
+        conversations.entry(channel).or_default().push(entry);
+    }
+    let app = handle.app_handle();
+    tokio::task::spawn_blocking(move || {
+        let store = app.state::<Store>();
+        let logs = app.state::<Logs>();
+        for (channel, entries) in conversations {
+            if let Err(err) = logs.append(&store, &channel, entries) {
+                eprintln!("Failed to log evicted messages: {}", err);
+            }
+        }
+    });
+}
+
+// Everything that can be coalesced is held here until the window closes.
+// Messages aren't; they're emitted as they come so that ordering and latency are kept.
+#[derive(Debug, Default)]
+pub struct Batch {
+    bookmarks: bool,
+    friends: bool,
+    channels: HashSet<Channel>,
+    characters: bool,
+}
+
+pub async fn handle_events<R: Runtime>(handle: AppHandle<R>, mut receiver: Receiver<AccountEvent>) {
+    // The last character version sent out per account, so the next delta starts where this one left off.
+    let mut character_versions: HashMap<String, u64> = HashMap::new();
+    loop {
+        // Every account heard from gets flushed, batch or not: the cache queues some changes
+        // (member lists, room lists) that don't come with an event of their own.
+        let mut batches: HashMap<String, Batch> = HashMap::new();
+        match timeout(QUEUE_TICK, receiver.recv()).await {
+            Ok(Some(event)) => {
+                batches.entry(event.account.clone()).or_default();
+                handle_event(&handle, &mut batches, event).await;
+            }
+            Ok(None) => break,
+            // Nothing's come in for a while, but the cache may still be holding something.
+            Err(_) => {
+                for (account, _) in handle.state::<MaybeClient>().all().await {
+                    batches.entry(account).or_default();
+                }
+            }
+        }
+
+        // On login, the server sends status for every online character in quick succession.
+        // Soak up everything that arrives in the window and send it as one payload.
+        let deadline = Instant::now() + BATCH_WINDOW;
+        let mut closed = false;
+        loop {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(event)) => {
+                    batches.entry(event.account.clone()).or_default();
+                    handle_event(&handle, &mut batches, event).await
+                }
+                Ok(None) => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        for (account, batch) in batches {
+            let character_version = character_versions.entry(account.clone()).or_default();
+            flush_batch(&handle, &account, batch, character_version).await;
+        }
+        if closed {
+            break;
+        }
+    }
+}
+
+pub async fn handle_event<R: Runtime>(
+    handle: &impl Manager<R>,
+    batches: &mut HashMap<String, Batch>,
+    event: AccountEvent,
+) {
+    let AccountEvent { account, event } = event;
     match event {
-        UpdateEvent::Bookmarks => handle.emit_all("update_bookmark", ()),
-        UpdateEvent::Channel(chan) => handle.emit_all("update_channel", chan),
-        UpdateEvent::Character(character) => handle.emit_all("update_character", character),
-        UpdateEvent::Friends => handle.emit_all("update_friends", ()),
         UpdateEvent::Message(channel, message) => {
-            handle.emit_all("message", EventMessage { channel, message })
+            if let Some(unread) = update_unread(handle, &account, &channel, &message).await {
+                emit(handle, &account, "unread_changed", unread);
+            }
+            emit(
+                handle,
+                &account,
+                "message",
+                EventMessage { channel, message },
+            );
+            log_evicted(handle, &account).await;
+        }
+        UpdateEvent::Invite(invite) => emit(handle, &account, "invite", invite),
+        UpdateEvent::Bookmarks => batches.entry(account).or_default().bookmarks = true,
+        UpdateEvent::Channel(chan) => {
+            batches.entry(account).or_default().channels.insert(chan);
+        }
+        UpdateEvent::Character(_) => batches.entry(account).or_default().characters = true,
+        UpdateEvent::Friends => batches.entry(account).or_default().friends = true,
+    }
+}
+
+// The payloads carry the data itself, so the frontend doesn't have to ask for it again.
+async fn flush_batch<R: Runtime>(
+    handle: &impl Manager<R>,
+    account: &str,
+    mut batch: Batch,
+    character_version: &mut u64,
+) {
+    let client = match handle.state::<MaybeClient>().get(Some(account)).await {
+        Some(client) => client,
+        None => return,
+    };
+    if batch.bookmarks {
+        if let Ok(bookmarks) = client.cache.get_bookmarks() {
+            emit(handle, account, "update_bookmarks", bookmarks.into_owned());
         }
     }
-    .expect("Failed to emit event");
+    if batch.friends {
+        if let Ok(friends) = client.cache.get_friends() {
+            emit(handle, account, "update_friends", friends.into_owned());
+        }
+    }
+    if batch.characters {
+        let changes = client.cache.take_presence_changes();
+        let alerts = handle.state::<Watch>().alerts(&client.cache, &changes);
+        let mut online = Vec::new();
+        let mut offline = Vec::new();
+        for change in changes {
+            match change.kind {
+                PresenceKind::Online => online.push(change),
+                PresenceKind::Offline => {
+                    // Logging off takes them out of every channel they were in.
+                    batch.channels.extend(change.channels.iter().cloned());
+                    offline.push(change);
+                }
+                PresenceKind::Status => (),
+            }
+        }
+        if !alerts.is_empty() {
+            emit(handle, account, "watch_alert", alerts);
+        }
+        if !online.is_empty() {
+            emit(handle, account, "character_online", online);
+        }
+        if !offline.is_empty() {
+            emit(handle, account, "character_offline", offline);
+        }
+    }
+    let channel_changes = client.cache.take_channel_changes();
+    if !channel_changes.is_empty() {
+        emit(handle, account, "channel_changed", channel_changes);
+    }
+    // Nothing tells us when a room list comes in, so its changes go out with whatever's flushed next,
+    // or on the next quiet tick.
+    let channel_list_changes = client.cache.take_channel_list_changes();
+    if !channel_list_changes.is_empty() {
+        emit(
+            handle,
+            account,
+            "channel_list_updated",
+            channel_list_changes,
+        );
+    }
+    let list_changes = client.cache.take_list_changes();
+    if !list_changes.is_empty() {
+        emit(handle, account, "list_changed", list_changes);
+    }
+    let mut joined = Vec::new();
+    let mut left = Vec::new();
+    for change in client.cache.take_member_changes() {
+        if !change.joined {
+            left.push(EventMemberLeft {
+                channel: change.channel,
+                character: change.character,
+            });
+        } else if let Some(member) = client
+            .cache
+            .get_channel_member(&change.channel, change.character)
+        {
+            joined.push(EventMemberJoined {
+                channel: change.channel,
+                member,
+            });
+        }
+    }
+    if !joined.is_empty() {
+        emit(handle, account, "channel_member_joined", joined);
+    }
+    if !left.is_empty() {
+        emit(handle, account, "channel_member_left", left);
+    }
+    if !batch.channels.is_empty() {
+        let channels: HashMap<Channel, Arc<ChannelData>> = batch
+            .channels
+            .into_iter()
+            .filter_map(|v| client.cache.channel_view(&v).map(|data| (v, data)))
+            .collect();
+        emit(handle, account, "update_channels", channels);
+    }
+    if batch.characters {
+        // Either the log was overrun or the client was replaced; deltas can't bridge that.
+        match client.cache.character_delta(*character_version) {
+            Some(delta) => {
+                *character_version = delta.to;
+                emit(handle, account, "characters_delta", delta);
+            }
+            None => {
+                *character_version = client.cache.character_version();
+                emit(handle, account, "characters_resync", *character_version);
+            }
+        }
+    }
+}
+
+pub(crate) fn emit<R: Runtime, S: Serialize + Clone>(
+    handle: &impl Manager<R>,
+    account: &str,
+    event: &str,
+    data: S,
+) {
+    if let Err(err) = handle.emit_all(event, ForAccount { account, data }) {
+        eprintln!("Failed to emit event {}: {}", event, err);
+    }
 }
@@ -0,0 +1,508 @@
+// Writing a conversation out to a file, for sharing scenes or keeping them somewhere else.
+// Text is laid out like the official client's logs; HTML is a single file with nothing to fetch.
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use f_chat_rs::{
+    data::{MessageChannel, MessageContent},
+    util::Timestamp,
+};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, State};
+use thiserror::Error;
+
+use crate::{
+    cache::{MessageContext, MessageRecord},
+    data, logs,
+    store::{write_atomic, Store, StoreError},
+    HuskyClient, MaybeClient,
+};
+
+// The colours used for names in the character lists.
+const GENDER_COLOURS: &[(&str, &str)] = &[
+    ("Male", "#3c9ae8"),
+    ("Male-herm", "#2b4acb"),
+    ("Herm", "#854eca"),
+    ("Shemale", "#ab7ae0"),
+    ("Female", "#e0529c"),
+    ("Cunt-boy", "#8bbb11"),
+    ("Transgender", "#d87a16"),
+];
+const DEFAULT_COLOUR: &str = "#bfbfbf";
+// The only colours F-Chat's [color] accepts.
+const BBCODE_COLOURS: &[&str] = &[
+    "red", "blue", "white", "yellow", "pink", "gray", "green", "orange", "purple", "black",
+    "brown", "cyan",
+];
+
+#[derive(Error, Debug, Serialize)]
+pub enum ExportError {
+    #[error("Export stopped: {0}")]
+    Interrupted(String),
+    #[error("Cache error: {0}")]
+    Cache(String),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Text,
+    Html,
+    Json,
+}
+
+// Either end can be left open.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExportRange {
+    pub from: Option<Timestamp>,
+    pub to: Option<Timestamp>,
+}
+
+impl ExportRange {
+    fn contains(&self, at: &Timestamp) -> bool {
+        self.from.map_or(true, |from| *at >= from) && self.to.map_or(true, |to| *at <= to)
+    }
+}
+
+fn timestamp(at: &Timestamp) -> String {
+    at.with_timezone(&Local)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+// "/me's" and friends attach to the name rather than following it.
+fn emote_separator(text: &str) -> &'static str {
+    if text.starts_with('\'') || text.starts_with(',') {
+        ""
+    } else {
+        " "
+    }
+}
+
+// Terms and results carry their own sign, so "-2" is joined on as "1d20-2", not "1d20+-2".
+fn join_rolls(rolls: &[String], results: &[i32], total: i32) -> String {
+    let mut dice = String::new();
+    for (i, roll) in rolls.iter().enumerate() {
+        if i > 0 && !roll.starts_with('-') {
+            dice.push('+');
+        }
+        dice.push_str(roll);
+    }
+    let mut sums = String::new();
+    for (i, result) in results.iter().enumerate() {
+        match (i, *result < 0) {
+            (0, _) => sums.push_str(&result.to_string()),
+            (_, true) => sums.push_str(&format!(" - {}", -result)),
+            (_, false) => sums.push_str(&format!(" + {}", result)),
+        }
+    }
+    format!("{}: {} = {}", dice, sums, total)
+}
+
+// Rooms get retitled mid-log; each title is noted where it took over.
+fn retitled<'a>(record: &'a MessageRecord, last: &mut Option<&'a str>) -> Option<&'a str> {
+    let title = record.sent_as.title.as_deref()?;
+    if *last == Some(title) {
+        return None;
+    }
+    *last = Some(title);
+    Some(title)
+}
+
+pub fn to_text(records: &[MessageRecord]) -> String {
+    let mut out = String::new();
+    let mut title = None;
+    for record in records {
+        if let Some(title) = retitled(record, &mut title) {
+            out.push_str(&format!("== {} ==\n", title));
+        }
+        let message = &record.message;
+        let name = data::name(&message.character);
+        let line = match &message.content {
+            MessageContent::Message(text) => format!("{}: {}", name, bbcode_to_text(text)),
+            MessageContent::Emote(text) => {
+                format!("*{}{}{}", name, emote_separator(text), bbcode_to_text(text))
+            }
+            MessageContent::Roll(rolls, results, total) => {
+                format!("{} rolls {}", name, join_rolls(rolls, results, *total))
+            }
+            MessageContent::Bottle(target) => {
+                format!("{} spins the bottle: {}", name, data::name(target))
+            }
+        };
+        out.push_str(&format!("[{}] {}\n", timestamp(&message.timestamp), line));
+    }
+    out
+}
+
+// The heading is what the room was called when the log starts, which may not be what it's called now.
+pub fn to_html(title: &str, records: &[MessageRecord]) -> String {
+    let mut last_title = records.first().and_then(|v| v.sent_as.title.as_deref());
+    let title = last_title.unwrap_or(title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        escape(title),
+        STYLE,
+        escape(title)
+    );
+    for record in records {
+        if let Some(title) = retitled(record, &mut last_title) {
+            out.push_str(&format!(
+                "<div class=\"notice\">Room retitled to <b>{}</b></div>\n",
+                escape(title)
+            ));
+        }
+        let message = &record.message;
+        let gender = record
+            .sent_as
+            .gender
+            .or(record.current.gender)
+            .map(|v| data::name(&v))
+            .unwrap_or_default();
+        let colour = GENDER_COLOURS
+            .iter()
+            .find(|v| v.0 == gender)
+            .map_or(DEFAULT_COLOUR, |v| v.1);
+        let name = format!(
+            "<span class=\"name\" style=\"color: {}\">{}</span>",
+            colour,
+            escape(&data::name(&message.character))
+        );
+        let (class, line) = match &message.content {
+            MessageContent::Message(text) => {
+                ("message", format!("{}: {}", name, bbcode_to_html(text)))
+            }
+            MessageContent::Emote(text) => (
+                "emote",
+                format!("*{}{}{}", name, emote_separator(text), bbcode_to_html(text)),
+            ),
+            MessageContent::Roll(rolls, results, total) => (
+                "roll",
+                format!(
+                    "{} rolls {}",
+                    name,
+                    escape(&join_rolls(rolls, results, *total))
+                ),
+            ),
+            MessageContent::Bottle(target) => (
+                "bottle",
+                format!(
+                    "{} spins the bottle: <b>{}</b>",
+                    name,
+                    escape(&data::name(target))
+                ),
+            ),
+        };
+        out.push_str(&format!(
+            "<div class=\"{}\"><span class=\"time\">[{}]</span> {}</div>\n",
+            class,
+            timestamp(&message.timestamp),
+            line
+        ));
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+const STYLE: &str =
+    "body { background: #1f1f1f; color: #d9d9d9; font-family: sans-serif; font-size: 14px; }
+.time { color: #8c8c8c; }
+.name { font-weight: bold; }
+.emote { font-style: italic; }
+.roll, .bottle { color: #a6a6a6; }
+.notice { color: #8c8c8c; text-align: center; margin: 0.5em 0; }
+.spoiler { background: #d9d9d9; }
+.spoiler:hover { background: none; }
+";
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            '\n' => out.push_str("<br>"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Debug)]
+enum Token<'a> {
+    Text(&'a str),
+    Open(String, Option<&'a str>),
+    Close(String),
+}
+
+// Splits text into tags and the text between them. Anything that looks like a tag but isn't
+// well formed is left as text, and [noparse] swallows everything up to its closing tag.
+fn tokenize(mut text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    while let Some(start) = text.find('[') {
+        if start > 0 {
+            tokens.push(Token::Text(&text[..start]));
+        }
+        let rest = &text[start..];
+        let end = match rest.find(']') {
+            Some(end) if !rest[1..end].contains('[') => end,
+            _ => {
+                tokens.push(Token::Text("["));
+                text = &rest[1..];
+                continue;
+            }
+        };
+        let inner = &rest[1..end];
+        text = &rest[end + 1..];
+        if let Some(name) = inner.strip_prefix('/') {
+            tokens.push(Token::Close(name.to_lowercase()));
+            continue;
+        }
+        let (name, arg) = match inner.split_once('=') {
+            Some((name, arg)) => (name.to_lowercase(), Some(arg)),
+            None => (inner.to_lowercase(), None),
+        };
+        if name == "noparse" {
+            match text.to_ascii_lowercase().find("[/noparse]") {
+                Some(close) => {
+                    tokens.push(Token::Text(&text[..close]));
+                    text = &text[close + "[/noparse]".len()..];
+                }
+                None => {
+                    tokens.push(Token::Text(text));
+                    text = "";
+                }
+            }
+            continue;
+        }
+        tokens.push(Token::Open(name, arg));
+    }
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    tokens
+}
+
+// These take their text as an argument (a name, an address), rather than formatting it.
+fn content_tag(name: &str) -> bool {
+    matches!(name, "user" | "icon" | "eicon" | "url")
+}
+
+fn simple_tag(name: &str) -> Option<(&'static str, &'static str)> {
+    Some(match name {
+        "b" => ("<b>", "</b>"),
+        "i" => ("<i>", "</i>"),
+        "u" => ("<u>", "</u>"),
+        "s" => ("<s>", "</s>"),
+        "sup" => ("<sup>", "</sup>"),
+        "sub" => ("<sub>", "</sub>"),
+        "spoiler" => ("<span class=\"spoiler\">", "</span>"),
+        _ => return None,
+    })
+}
+
+fn safe_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+// Collects the text up to `[/name]`, if that's all there is before it.
+fn take_content<'a>(tokens: &[Token<'a>], name: &str) -> Option<(String, usize)> {
+    let mut content = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Text(text) => content.push_str(text),
+            Token::Close(close) if close == name => return Some((content, i + 1)),
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn render_content_tag(name: &str, arg: Option<&str>, content: &str) -> String {
+    let content = content.trim();
+    match name {
+        "user" => format!(
+            "<a href=\"https://www.f-list.net/c/{}\">{}</a>",
+            escape(content),
+            escape(content)
+        ),
+        "icon" => format!(
+            "<a href=\"https://www.f-list.net/c/{}\"><img src=\"https://static.f-list.net/images/avatar/{}.png\" alt=\"{}\" width=\"50\" height=\"50\"></a>",
+            escape(content),
+            escape(&content.to_lowercase()),
+            escape(content)
+        ),
+        "eicon" => format!(
+            "<img src=\"https://static.f-list.net/images/eicon/{}.gif\" alt=\"{}\" width=\"50\" height=\"50\">",
+            escape(&content.to_lowercase()),
+            escape(content)
+        ),
+        _ => {
+            // [url=address]text[/url] or [url]address[/url]
+            let url = arg.unwrap_or(content).trim();
+            let text = if content.is_empty() { url } else { content };
+            if safe_url(url) {
+                format!("<a href=\"{}\">{}</a>", escape(url), escape(text))
+            } else {
+                escape(text)
+            }
+        }
+    }
+}
+
+pub fn bbcode_to_html(text: &str) -> String {
+    let tokens = tokenize(text);
+    let mut out = String::new();
+    let mut open: Vec<(String, &'static str)> = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            Token::Text(text) => out.push_str(&escape(text)),
+            Token::Open(name, arg) if content_tag(name) => {
+                if let Some((content, used)) = take_content(&tokens[i + 1..], name) {
+                    out.push_str(&render_content_tag(name, *arg, &content));
+                    i += used + 1;
+                    continue;
+                }
+                out.push_str(&escape(&format!("[{}]", name)));
+            }
+            Token::Open(name, arg) => {
+                if name == "color" {
+                    let colour = arg.map(|v| v.trim().to_lowercase()).unwrap_or_default();
+                    if BBCODE_COLOURS.contains(&colour.as_str()) {
+                        out.push_str(&format!("<span style=\"color: {}\">", colour));
+                        open.push((name.clone(), "</span>"));
+                    }
+                } else if let Some((start, end)) = simple_tag(name) {
+                    out.push_str(start);
+                    open.push((name.clone(), end));
+                } else {
+                    out.push_str(&escape(&format!(
+                        "[{}{}]",
+                        name,
+                        arg.map(|v| format!("={}", v)).unwrap_or_default()
+                    )));
+                }
+            }
+            Token::Close(name) => {
+                // Closing an outer tag closes everything opened inside it.
+                if let Some(pos) = open.iter().rposition(|v| &v.0 == name) {
+                    for (_, end) in open.drain(pos..).rev() {
+                        out.push_str(end);
+                    }
+                } else if name != "color" {
+                    out.push_str(&escape(&format!("[/{}]", name)));
+                }
+            }
+        }
+        i += 1;
+    }
+    for (_, end) in open.drain(..).rev() {
+        out.push_str(end);
+    }
+    out
+}
+
+// Formatting goes, text and links stay.
+pub fn bbcode_to_text(text: &str) -> String {
+    let tokens = tokenize(text);
+    let mut out = String::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            Token::Text(text) => out.push_str(text),
+            Token::Open(name, Some(url)) if name == "url" => {
+                if let Some((content, used)) = take_content(&tokens[i + 1..], name) {
+                    out.push_str(&format!("{} ({})", content, url));
+                    i += used + 1;
+                    continue;
+                }
+            }
+            Token::Open(name, _) if content_tag(name) || name == "color" => (),
+            Token::Open(name, _) if simple_tag(name).is_some() => (),
+            Token::Close(name)
+                if content_tag(name) || name == "color" || simple_tag(name).is_some() => {}
+            Token::Open(name, arg) => out.push_str(&format!(
+                "[{}{}]",
+                name,
+                arg.map(|v| format!("={}", v)).unwrap_or_default()
+            )),
+            Token::Close(name) => out.push_str(&format!("[/{}]", name)),
+        }
+        i += 1;
+    }
+    out
+}
+
+// Works logged out too, from the logs alone. It's all file reads and writes, so it runs off the async workers.
+#[tauri::command]
+pub async fn export_logs(
+    app: AppHandle,
+    client: State<'_, MaybeClient>,
+    channel: data::MessageChannel,
+    account: Option<String>,
+    range: Option<ExportRange>,
+    format: ExportFormat,
+    path: PathBuf,
+) -> Result<usize, ExportError> {
+    let client = crate::for_message_channel(&client, &channel, account.as_deref()).await;
+    let channel: MessageChannel = channel.into();
+    let range = range.unwrap_or_default();
+    tokio::task::spawn_blocking(move || {
+        export(&app, client.as_deref(), &channel, &range, format, &path)
+    })
+    .await
+    .map_err(|err| ExportError::Interrupted(err.to_string()))?
+}
+
+fn export(
+    app: &AppHandle,
+    client: Option<&HuskyClient>,
+    channel: &MessageChannel,
+    range: &ExportRange,
+    format: ExportFormat,
+    path: &Path,
+) -> Result<usize, ExportError> {
+    // The cache has what's come in since login, less anything pushed out to the logs since.
+    // The logs have the rest, and anything imported; where they overlap, the cache wins.
+    let cached = client.map_or_else(Vec::new, |v| v.cache.get_message_records(channel, None));
+    let first_cached = cached.first().map(|v| v.message.timestamp);
+    let store = app.state::<Store>();
+    let records: Vec<MessageRecord> = logs::load_log(&store, channel, range.from, range.to)
+        .into_iter()
+        .filter(|v| first_cached.map_or(true, |first| v.message.timestamp < first))
+        .map(|v| MessageRecord {
+            current: client.map_or_else(MessageContext::default, |client| {
+                client.cache.current_context(channel, &v.message.character)
+            }),
+            message: v.message,
+            sent_as: v.sent_as,
+        })
+        .chain(cached)
+        .filter(|v| range.contains(&v.message.timestamp))
+        .collect();
+
+    let contents = match format {
+        ExportFormat::Text => to_text(&records),
+        ExportFormat::Html => {
+            let title = match channel {
+                MessageChannel::Channel(id) => client
+                    .and_then(|v| v.cache.channel_view(id))
+                    .map_or_else(|| data::name(id), |v| v.title.clone()),
+                MessageChannel::PrivateMessage(own, other) => {
+                    format!("{} and {}", data::name(own), data::name(other))
+                }
+            };
+            to_html(&title, &records)
+        }
+        ExportFormat::Json => serde_json::to_string_pretty(&records).map_err(StoreError::from)?,
+    };
+    write_atomic(path, contents.as_bytes())?;
+    Ok(records.len())
+}
@@ -0,0 +1,334 @@
+// Bringing in history from other clients. The official desktop client and Horizon (a fork of it)
+// share a log format: a directory per own character, holding logs/<conversation> files where each
+// record is laid out as
+//
+//   u32 time (unix seconds), u8 type, u8 sender length, sender, u16 text length, text, u16 record size
+//
+// all little-endian. Channel conversations are named "#<channel id>", PMs by the other character,
+// both in lowercase. The .idx files next to them are only indexes by day, and are skipped.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::{TimeZone, Utc};
+use f_chat_rs::data::{Channel, Character, Message, MessageChannel, MessageContent};
+use serde::Serialize;
+use tauri::{AppHandle, Manager};
+use thiserror::Error;
+
+use crate::{data, export::bbcode_to_text, logs::Logs, store::Store};
+
+// Message types in the official client's logs.
+const TYPE_MESSAGE: u8 = 0;
+const TYPE_ACTION: u8 = 1;
+const TYPE_AD: u8 = 2;
+const TYPE_ROLL: u8 = 3;
+const TYPE_WARN: u8 = 4;
+// Event (5) and broadcast (6) have no sender, so there's nothing in Husky's model for them.
+
+#[derive(Error, Debug, Serialize)]
+pub enum ImportError {
+    #[error("No logs found under {0}")]
+    NotFound(String),
+    #[error("Import stopped: {0}")]
+    Interrupted(String),
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportSummary {
+    pub files: usize,
+    pub imported: usize,
+    // Already logged, or of a kind there's nowhere to put.
+    pub skipped: usize,
+    // Records that couldn't be read, or had nowhere to go. A damaged stretch of a file counts once,
+    // since there's no telling how many records it held.
+    pub failed: usize,
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportProgress {
+    pub file: String,
+    pub done: usize,
+    pub total: usize,
+}
+
+struct ImportFile {
+    own_character: String,
+    key: String,
+    path: PathBuf,
+}
+
+struct Record {
+    time: u32,
+    kind: u8,
+    sender: String,
+    text: String,
+}
+
+// Accepts the client's data directory, a single character's directory, or its logs directory.
+fn find_files(path: &Path) -> Vec<ImportFile> {
+    let character_dirs: Vec<PathBuf> = if path.ends_with("logs") {
+        path.parent().map(Path::to_path_buf).into_iter().collect()
+    } else if path.join("logs").is_dir() {
+        vec![path.to_path_buf()]
+    } else {
+        fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.ok().map(|v| v.path()))
+            .filter(|v| v.join("logs").is_dir())
+            .collect()
+    };
+
+    let mut files = Vec::new();
+    for dir in character_dirs {
+        let own_character = match dir.file_name().and_then(|v| v.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        for entry in fs::read_dir(dir.join("logs"))
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let path = entry.path();
+            if !path.is_file() || path.extension().map_or(false, |v| v == "idx") {
+                continue;
+            }
+            if let Some(key) = path
+                .file_name()
+                .and_then(|v| v.to_str())
+                .map(str::to_string)
+            {
+                files.push(ImportFile {
+                    own_character: own_character.clone(),
+                    key,
+                    path,
+                });
+            }
+        }
+    }
+    files
+}
+
+fn read_u16(raw: &[u8], at: usize) -> Option<usize> {
+    Some(u16::from_le_bytes([*raw.get(at)?, *raw.get(at + 1)?]) as usize)
+}
+
+// Returns the record at `at`, and where the next one starts.
+fn read_record(raw: &[u8], at: usize) -> Option<(Record, usize)> {
+    let time = u32::from_le_bytes(raw.get(at..at + 4)?.try_into().ok()?);
+    let kind = *raw.get(at + 4)?;
+    let sender_length = *raw.get(at + 5)? as usize;
+    let sender = raw.get(at + 6..at + 6 + sender_length)?;
+    let text_at = at + 6 + sender_length;
+    let text_length = read_u16(raw, text_at)?;
+    let text = raw.get(text_at + 2..text_at + 2 + text_length)?;
+    let end = text_at + 2 + text_length;
+    // The trailing size lets the client read backwards; it has to agree with what we read.
+    if read_u16(raw, end)? != end - at {
+        return None;
+    }
+    let record = Record {
+        time,
+        kind,
+        sender: String::from_utf8(sender.to_vec()).ok()?,
+        text: String::from_utf8(text.to_vec()).ok()?,
+    };
+    Some((record, end + 2))
+}
+
+// Reads every record it can. Past one that doesn't make sense, it looks for the next place a
+// whole record reads back (its trailing size has to agree), and carries on from there.
+// Returns the records and how many damaged stretches were passed over.
+fn parse_records(raw: &[u8]) -> (Vec<Record>, usize) {
+    let mut records = Vec::new();
+    let mut damaged = 0;
+    let mut at = 0;
+    while at < raw.len() {
+        match read_record(raw, at) {
+            Some((record, next)) => {
+                records.push(record);
+                at = next;
+            }
+            None => {
+                damaged += 1;
+                match (at + 1..raw.len()).find_map(|start| read_record(raw, start)) {
+                    Some((record, next)) => {
+                        records.push(record);
+                        at = next;
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+    (records, damaged)
+}
+
+// The client stores rolls as the server's text, e.g. "rolls 2d6+1: [b]3[/b] + [b]4[/b] + [b]1[/b] = [b]8[/b]".
+fn parse_roll(text: &str) -> Option<MessageContent> {
+    let text = bbcode_to_text(text);
+    if let Some(target) = text.split("spins the bottle:").nth(1) {
+        return Some(MessageContent::Bottle(data::from_name(target.trim())?));
+    }
+    let (dice, results) = text.split_once("rolls ")?.1.split_once(':')?;
+    let dice: Vec<String> = dice.split('+').map(|v| v.trim().to_string()).collect();
+    let (results, total) = match results.split_once('=') {
+        Some((results, total)) => (results, total),
+        None => (results, results),
+    };
+    let results = results
+        .split('+')
+        .map(|v| v.trim().parse().ok())
+        .collect::<Option<Vec<i32>>>()?;
+    Some(MessageContent::Roll(
+        dice,
+        results,
+        total.trim().parse().ok()?,
+    ))
+}
+
+// Kinds with no sender are skipped; otherwise anything that doesn't come out is a failure.
+enum Converted {
+    Message(Message),
+    Skipped,
+    Failed,
+}
+
+fn to_message(record: Record) -> Converted {
+    let content = match record.kind {
+        TYPE_MESSAGE | TYPE_AD | TYPE_WARN => MessageContent::Message(record.text),
+        TYPE_ACTION => MessageContent::Emote(record.text),
+        // Anything we can't make out is kept as the server worded it.
+        TYPE_ROLL => parse_roll(&record.text)
+            .unwrap_or_else(|| MessageContent::Emote(bbcode_to_text(&record.text))),
+        _ => return Converted::Skipped,
+    };
+    let timestamp = Utc.timestamp_opt(record.time as i64, 0).single();
+    match (timestamp, data::from_name(&record.sender)) {
+        (Some(timestamp), Some(character)) => Converted::Message(Message {
+            timestamp,
+            character,
+            content,
+        }),
+        _ => Converted::Failed,
+    }
+}
+
+// PM logs are named in lowercase. The proper name is whatever the other side signed their messages with.
+// Channels are too, but channel logs are looked up without case (see logs::log_dir), so that's fine.
+fn conversation(file: &ImportFile, records: &[Record]) -> Option<MessageChannel> {
+    let own: Character = data::from_name(&file.own_character)?;
+    if let Some(channel) = file.key.strip_prefix('#') {
+        let channel: Channel = data::from_name(channel)?;
+        return Some(MessageChannel::Channel(channel));
+    }
+    let other = records
+        .iter()
+        .find(|v| v.sender.to_lowercase() == file.key)
+        .map_or(file.key.as_str(), |v| v.sender.as_str());
+    Some(MessageChannel::PrivateMessage(own, data::from_name(other)?))
+}
+
+fn import_file(store: &Store, logs: &Logs, file: &ImportFile, summary: &mut ImportSummary) {
+    let raw = match fs::read(&file.path) {
+        Ok(raw) => raw,
+        // Nothing in it could be read, so it counts as one damaged stretch.
+        Err(err) => {
+            summary.failed += 1;
+            summary
+                .errors
+                .push(format!("{}: {}", file.path.display(), err));
+            return;
+        }
+    };
+    let (records, damaged) = parse_records(&raw);
+    if damaged > 0 {
+        summary.failed += damaged;
+        summary.errors.push(format!(
+            "{}: {} damaged stretches skipped",
+            file.path.display(),
+            damaged
+        ));
+    }
+    let channel = match conversation(file, &records) {
+        Some(channel) => channel,
+        None => {
+            summary.failed += records.len();
+            summary.errors.push(format!(
+                "{}: unrecognised conversation",
+                file.path.display()
+            ));
+            return;
+        }
+    };
+
+    let mut messages = Vec::new();
+    let mut unreadable = 0;
+    for record in records {
+        match to_message(record) {
+            Converted::Message(message) => messages.push(message),
+            Converted::Skipped => summary.skipped += 1,
+            Converted::Failed => unreadable += 1,
+        }
+    }
+    if unreadable > 0 {
+        summary.failed += unreadable;
+        summary.errors.push(format!(
+            "{}: {} records with a bad time or sender",
+            file.path.display(),
+            unreadable
+        ));
+    }
+    let read = messages.len();
+    match logs.merge(store, &channel, messages) {
+        Ok(added) => {
+            summary.imported += added;
+            summary.skipped += read - added;
+        }
+        Err(err) => {
+            summary.failed += read;
+            summary
+                .errors
+                .push(format!("{}: {}", file.path.display(), err));
+        }
+    }
+}
+
+// Importing the same logs twice is harmless; anything already there is skipped.
+// It's all file reads and writes, so it runs off the async workers.
+#[tauri::command]
+pub async fn import_logs(app: AppHandle, path: PathBuf) -> Result<ImportSummary, ImportError> {
+    tokio::task::spawn_blocking(move || import_all(&app, &path))
+        .await
+        .map_err(|err| ImportError::Interrupted(err.to_string()))?
+}
+
+fn import_all(app: &AppHandle, path: &Path) -> Result<ImportSummary, ImportError> {
+    let store = app.state::<Store>();
+    let logs = app.state::<Logs>();
+    let files = find_files(path);
+    if files.is_empty() {
+        return Err(ImportError::NotFound(path.display().to_string()));
+    }
+
+    let mut summary = ImportSummary {
+        files: files.len(),
+        ..Default::default()
+    };
+    for (done, file) in files.iter().enumerate() {
+        let progress = ImportProgress {
+            file: format!("{}/{}", file.own_character, file.key),
+            done,
+            total: files.len(),
+        };
+        if let Err(err) = app.emit_all("import_progress", progress) {
+            eprintln!("Failed to emit event import_progress: {}", err);
+        }
+        import_file(&store, &logs, file, &mut summary);
+    }
+    Ok(summary)
+}
@@ -0,0 +1,255 @@
+// Conversation history that outlives the session, under logs/ with a directory per conversation
+// and a file per day (UTC). The cache only ever holds what's come in since login; this is where
+// anything older lives.
+//
+// Unlike the rest of the store, these are added to rather than written whole: each day is JSON
+// lines, and new messages are appended. A busy channel costs a write of what's new, not a rewrite
+// of everything it's ever said, and nothing here ever writes over a file that's already there.
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use f_chat_rs::{
+    data::{Message, MessageChannel},
+    util::Timestamp,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::MessageContext,
+    data,
+    store::{Store, StoreError},
+};
+
+const LOG_DIR: &str = "logs";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    #[serde(flatten)]
+    pub message: Message,
+    // Imports don't have this; other clients don't keep it.
+    #[serde(default)]
+    pub sent_as: MessageContext,
+}
+
+impl From<Message> for LogEntry {
+    fn from(message: Message) -> Self {
+        LogEntry {
+            message,
+            sent_as: MessageContext::default(),
+        }
+    }
+}
+
+// Names can hold characters that filesystems won't, and some filesystems ignore case.
+// Only lowercase letters and digits are kept as they are; anything else becomes _ and its bytes
+// in hex. That can always be read back, so no two names end up sharing a file.
+pub fn encode_name(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            out.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                out.push_str(&format!("_{:02x}", byte));
+            }
+        }
+    }
+    out
+}
+
+// Channel ids don't care about case, and other clients' logs (so imports) have them in lowercase.
+pub fn log_dir(channel: &MessageChannel) -> String {
+    match channel {
+        MessageChannel::Channel(channel) => format!(
+            "{}/channel-{}",
+            LOG_DIR,
+            encode_name(&data::name(channel).to_lowercase())
+        ),
+        MessageChannel::PrivateMessage(own, other) => format!(
+            "{}/pm-{}-{}",
+            LOG_DIR,
+            encode_name(&data::name(own)),
+            encode_name(&data::name(other))
+        ),
+    }
+}
+
+fn day_name(dir: &str, at: &Timestamp) -> String {
+    format!("{}/{}.jsonl", dir, at.format("%Y-%m-%d"))
+}
+
+// A line that doesn't parse (a write cut short by a crash, say) is skipped, not fatal.
+// Nothing is ever written back from what's read here, so skipping can't lose anything.
+fn read_day(path: &Path) -> Vec<LogEntry> {
+    let raw = match fs::read_to_string(path) {
+        Ok(raw) => raw,
+        Err(_) => return Vec::new(),
+    };
+    raw.lines()
+        .filter(|v| !v.trim().is_empty())
+        .filter_map(|v| match serde_json::from_str(v) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                eprintln!(
+                    "Skipping unreadable log line in {}: {}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+fn append_day(path: &Path, entries: &[LogEntry]) -> Result<(), StoreError> {
+    let mut lines = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut lines, entry)?;
+        lines.push(b'\n');
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    // If the last write was cut short, start on a fresh line so only that one is lost.
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0u8];
+        file.seek(SeekFrom::Start(len - 1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+    }
+    file.write_all(&lines)?;
+    Ok(())
+}
+
+fn by_day(
+    dir: &str,
+    entries: impl IntoIterator<Item = LogEntry>,
+) -> HashMap<String, Vec<LogEntry>> {
+    let mut days: HashMap<String, Vec<LogEntry>> = HashMap::new();
+    for entry in entries {
+        days.entry(day_name(dir, &entry.message.timestamp))
+            .or_default()
+            .push(entry);
+    }
+    days
+}
+
+// Everything logged for the conversation between from and to (either end open), in time order.
+pub fn load_log(
+    store: &Store,
+    channel: &MessageChannel,
+    from: Option<Timestamp>,
+    to: Option<Timestamp>,
+) -> Vec<LogEntry> {
+    let dir = log_dir(channel);
+    // Day files are named so that comparing names compares days.
+    let first = from.map(|v| day_name(&dir, &v));
+    let last = to.map(|v| day_name(&dir, &v));
+    let mut days: Vec<String> = fs::read_dir(store.path(&dir))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|v| v.file_name().to_str().map(|v| format!("{}/{}", dir, v)))
+        .filter(|v| v.ends_with(".jsonl"))
+        .filter(|v| first.as_ref().map_or(true, |first| v >= first))
+        .filter(|v| last.as_ref().map_or(true, |last| v <= last))
+        .collect();
+    days.sort();
+
+    let mut log: Vec<LogEntry> = days
+        .iter()
+        .flat_map(|day| read_day(&store.path(day)))
+        .collect();
+    log.retain(|v| {
+        from.map_or(true, |from| v.message.timestamp >= from)
+            && to.map_or(true, |to| v.message.timestamp <= to)
+    });
+    // Imports can land in the middle of a day.
+    log.sort_by_key(|v| v.message.timestamp);
+    log
+}
+
+// Imported logs only have whole seconds, so that's what counts as "the same time".
+fn message_key(message: &Message) -> (i64, String) {
+    (
+        message.timestamp.timestamp(),
+        serde_json::to_string(&(&message.character, &message.content)).unwrap_or_default(),
+    )
+}
+
+// Writes to a conversation go one at a time, since eviction and imports can both be at it.
+#[derive(Debug, Default)]
+pub struct Logs {
+    writing: Mutex<()>,
+}
+
+impl Logs {
+    pub fn new() -> Self {
+        Logs::default()
+    }
+
+    // For messages we saw ourselves, which can't already be in the log.
+    pub fn append(
+        &self,
+        store: &Store,
+        channel: &MessageChannel,
+        entries: Vec<LogEntry>,
+    ) -> Result<usize, StoreError> {
+        let _writing = self.writing.lock().unwrap();
+        let added = entries.len();
+        for (day, entries) in by_day(&log_dir(channel), entries) {
+            append_day(&store.path(&day), &entries)?;
+        }
+        Ok(added)
+    }
+
+    // For imports, which can overlap with what's logged already. Matching is down to the second,
+    // and by count: the same short line twice in one second is two messages, but importing it
+    // again isn't. Returns how many messages were new.
+    pub fn merge(
+        &self,
+        store: &Store,
+        channel: &MessageChannel,
+        messages: Vec<Message>,
+    ) -> Result<usize, StoreError> {
+        let _writing = self.writing.lock().unwrap();
+        let mut added = 0;
+        let days = by_day(&log_dir(channel), messages.into_iter().map(LogEntry::from));
+        for (day, entries) in days {
+            let path = store.path(&day);
+            let mut logged: HashMap<(i64, String), usize> = HashMap::new();
+            for entry in read_day(&path) {
+                *logged.entry(message_key(&entry.message)).or_default() += 1;
+            }
+            let new: Vec<LogEntry> = entries
+                .into_iter()
+                .filter(|entry| match logged.get_mut(&message_key(&entry.message)) {
+                    Some(count) if *count > 0 => {
+                        *count -= 1;
+                        false
+                    }
+                    _ => true,
+                })
+                .collect();
+            if !new.is_empty() {
+                append_day(&path, &new)?;
+                added += new.len();
+            }
+        }
+        Ok(added)
+    }
+}
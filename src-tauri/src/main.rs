@@ -3,87 +3,336 @@
     windows_subsystem = "windows"
 )]
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
 
 use f_chat_rs::{
     cache::Cache,
     client::{Client, ClientBuilder},
-    data::{self as f_data, Channel, ChannelData, Character, CharacterData, Message},
+    data::{Channel, ChannelData, Character, CharacterData},
     protocol::Target,
+    util::Timestamp,
+};
+use tauri::{AppHandle, Manager, State};
+use tokio::{
+    sync::{mpsc::Sender, RwLock as AsyncRwLock},
+    task::JoinHandle,
 };
-use tauri::State;
-use tokio::sync::{mpsc::Sender, RwLock as AsyncRwLock};
 
 mod cache;
 mod data;
+mod dice;
+#[cfg(test)]
+mod dice_tests;
+mod drafts;
 mod event;
+mod export;
+mod import;
+mod logs;
+#[cfg(test)]
+mod mock_server;
+mod moderation;
+mod room;
+mod schedule;
+mod settings;
+mod store;
+#[cfg(test)]
+mod tests;
+mod vault;
+mod watch;
 
 // https://github.com/tauri-apps/tauri/issues/2533
 type AsyncVoid = Result<(), ()>;
 
+type HuskyClient = Client<event::EventHandler, cache::Cache>;
+
 // This is going to be used in almost every command.
+// One client per logged in account, oldest login first.
 struct MaybeClient {
-    client: AsyncRwLock<Option<Arc<Client<event::EventHandler, cache::Cache>>>>, // This is ugly.
+    clients: AsyncRwLock<Vec<(String, Arc<HuskyClient>)>>,
+    // What's driving each account's connections, so a replaced client can be stopped.
+    tasks: Mutex<HashMap<String, JoinHandle<()>>>,
 }
 type ClientState<'a> = State<'a, MaybeClient>;
 
-// client::ClientError is not yet Serialize, so cannot be used.
+impl MaybeClient {
+    fn new() -> Self {
+        MaybeClient {
+            clients: AsyncRwLock::new(Vec::new()),
+            tasks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The named account, or the most recent login if none is named.
+    async fn get(&self, account: Option<&str>) -> Option<Arc<HuskyClient>> {
+        let clients = self.clients.read().await;
+        match account {
+            Some(account) => clients.iter().find(|v| v.0 == account),
+            None => clients.last(),
+        }
+        .map(|v| v.1.clone())
+    }
+
+    // Whichever account has this character connected.
+    async fn for_session(&self, session: &Character) -> Option<Arc<HuskyClient>> {
+        let clients = self.clients.read().await;
+        clients
+            .iter()
+            .find(|v| v.1.get_session(session).is_some())
+            .map(|v| v.1.clone())
+    }
+
+    // Whichever account this character belongs to, connected or not.
+    async fn for_character(&self, character: &Character) -> Option<Arc<HuskyClient>> {
+        let clients = self.clients.read().await;
+        clients
+            .iter()
+            .find(|v| v.1.own_characters.contains(character))
+            .map(|v| v.1.clone())
+    }
+
+    async fn all(&self) -> Vec<(String, Arc<HuskyClient>)> {
+        self.clients.read().await.clone()
+    }
+
+    // Logging in again replaces the old client for that account. The old one is stopped first,
+    // or its sockets would stay open next to the new ones.
+    async fn insert(&self, account: String, client: Arc<HuskyClient>, task: JoinHandle<()>) {
+        let mut clients = self.clients.write().await;
+        if let Some(old) = self.tasks.lock().unwrap().insert(account.clone(), task) {
+            old.abort();
+        }
+        clients.retain(|v| v.0 != account);
+        clients.push((account, client));
+    }
+}
+
+// Where accounts log in and connect to. Only ever changed for tests.
+struct Endpoints {
+    api: String,
+    chat: String,
+}
+
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            api: "https://www.f-list.net/json/getApiTicket.php".to_string(),
+            chat: "wss://chat.f-list.net/chat2".to_string(),
+        }
+    }
+}
+
+// Builds a client for the account and swaps it in, returning the ticket it logged in with.
+// client::ClientError is not yet Serialize, so errors are flattened to strings.
+async fn connect_account(
+    client: &MaybeClient,
+    emitter: &Sender<event::AccountEvent>,
+    store: &store::Store,
+    settings: &settings::SettingsStore,
+    endpoints: &Endpoints,
+    username: String,
+    password: String,
+    ticket: Option<String>,
+) -> Result<String, String> {
+    let cache = cache::Cache::new();
+    let global = settings.get().global;
+    cache.set_retain_offline(global.retain_offline);
+    cache.set_retention(global.retention());
+    let markers: Vec<(data::MessageChannel, Timestamp)> = store.load(store::READ_MARKERS);
+    cache.load_read_markers(
+        markers
+            .into_iter()
+            .map(|(channel, last_read)| (channel.into(), last_read)),
+    );
+
+    let handler = event::EventHandler::new(username.clone(), emitter.clone());
+    let mut builder = ClientBuilder::new(handler)
+        .with_version("Husky".to_string(), "0.1".to_string())
+        .with_api_url(endpoints.api.clone())
+        .with_chat_url(endpoints.chat.clone())
+        .with_cache(cache);
+    if let Some(ticket) = ticket {
+        builder = builder.with_ticket(ticket);
+    }
+    let (new_client, receiver) = builder
+        .init(username.clone(), password)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    let new_client = Arc::new(new_client);
+    new_client
+        .cache
+        .set_own_characters(&new_client.own_characters);
+    let ticket = new_client.get_ticket();
+    let task = {
+        let new_client = new_client.clone();
+        tokio::spawn(async move {
+            let _ = new_client.start(receiver).await;
+        })
+    };
+    client.insert(username.clone(), new_client, task).await;
+
+    settings
+        .update(serde_json::json!({ "global": { "last_account": username } }))
+        .map_err(|err| err.to_string())?;
+    Ok(ticket)
+}
+
 #[tauri::command]
 async fn login(
     client: ClientState<'_>,
-    emitter: State<'_, Sender<event::UpdateEvent>>,
+    emitter: State<'_, Sender<event::AccountEvent>>,
+    store: State<'_, store::Store>,
+    settings: State<'_, settings::SettingsStore>,
+    endpoints: State<'_, Endpoints>,
+    vault: State<'_, vault::Vault>,
     username: String,
     password: String,
-) -> AsyncVoid {
-    let (new_client, receiver) =
-        ClientBuilder::new(event::EventHandler::new(emitter.inner().clone()))
-            .with_version("Husky".to_string(), "0.1".to_string())
-            .with_cache(cache::Cache::new())
-            .init(username, password)
-            .await
-            .expect("Failed to init client -- Later, turn this into non-fatal.");
-    let new_client = Arc::new(dbg!(new_client));
-    *client.client.write().await = Some(new_client.clone());
-    tokio::spawn(async move { new_client.start(receiver).await });
+    remember: Option<bool>,
+) -> Result<(), vault::VaultError> {
+    // Checked before connecting, so a vault that can't take the credentials doesn't leave
+    // a connected client behind an error.
+    let remember = remember.unwrap_or(false);
+    if remember {
+        vault.ensure_unlocked()?;
+    }
+    let ticket = connect_account(
+        &client,
+        &emitter,
+        &store,
+        &settings,
+        &endpoints,
+        username.clone(),
+        password.clone(),
+        None,
+    )
+    .await
+    .map_err(vault::VaultError::Login)?;
+
+    if remember {
+        vault.set_credentials(username, password, Some(vault::Ticket::new(ticket)))?;
+    }
+    Ok(())
+}
 
+// Auto-login, without the webview ever seeing the password.
+// With no username, it's whichever account logged in last.
+#[tauri::command]
+async fn login_saved(
+    client: ClientState<'_>,
+    emitter: State<'_, Sender<event::AccountEvent>>,
+    store: State<'_, store::Store>,
+    settings: State<'_, settings::SettingsStore>,
+    endpoints: State<'_, Endpoints>,
+    vault: State<'_, vault::Vault>,
+    username: Option<String>,
+) -> Result<(), vault::VaultError> {
+    let username = username
+        .or_else(|| settings.get().global.last_account)
+        .ok_or_else(|| vault::VaultError::NoCredentials(String::new()))?;
+    let credentials = vault.credentials(&username)?;
+    let saved = credentials.ticket.filter(|v| v.is_valid());
+
+    let ticket = connect_account(
+        &client,
+        &emitter,
+        &store,
+        &settings,
+        &endpoints,
+        username.clone(),
+        credentials.password,
+        saved.as_ref().map(|v| v.ticket.clone()),
+    )
+    .await
+    .map_err(vault::VaultError::Login)?;
+    // A reused ticket keeps the expiry it was issued with.
+    if saved.map_or(true, |v| v.ticket != ticket) {
+        vault.set_ticket(&username, vault::Ticket::new(ticket))?;
+    }
     Ok(())
 }
 
+#[derive(serde::Serialize, Debug)]
+struct AccountData {
+    account: String,
+    own_characters: Vec<Character>,
+    sessions: Vec<Character>,
+}
+
 #[tauri::command]
-async fn start_session(client: ClientState<'_>, character: Character) -> AsyncVoid {
-    let client_guard = client.client.read().await;
-    let client = client_guard
-        .as_ref()
-        .expect("Too optimistic... (start_session)");
+async fn get_accounts(client: ClientState<'_>) -> Result<Vec<AccountData>, ()> {
+    Ok(client
+        .all()
+        .await
+        .drain(..)
+        .map(|(account, client)| AccountData {
+            account,
+            own_characters: client.own_characters.clone(),
+            sessions: client
+                .get_sessions()
+                .drain(..)
+                .map(|v| v.character.clone())
+                .collect(),
+        })
+        .collect())
+}
+
+// Without an account, it's whichever account owns the character.
+#[tauri::command]
+async fn start_session(
+    client: ClientState<'_>,
+    scheduler: State<'_, schedule::Scheduler>,
+    character: Character,
+    account: Option<String>,
+) -> AsyncVoid {
+    let client = match account {
+        Some(account) => client.get(Some(&account)).await,
+        None => client.for_character(&character).await,
+    }
+    .expect("Too optimistic... (start_session)");
     client
         .connect(character)
         .await
         .expect("Failed to connect character");
+    // Scheduled messages dropped while it was logged out can be reported now.
+    scheduler.wake();
     Ok(())
 }
 
 #[tauri::command]
-async fn get_own_characters(client: ClientState<'_>) -> Result<Vec<Character>, ()> {
-    let client_guard = client.client.read().await;
-    let client = client_guard
-        .as_ref()
+async fn get_own_characters(
+    client: ClientState<'_>,
+    account: Option<String>,
+) -> Result<Vec<Character>, ()> {
+    let client = client
+        .get(account.as_deref())
+        .await
         .expect("Too optimistic! (get_own_characters)");
     Ok(client.own_characters.clone())
 }
 
 #[tauri::command]
-async fn get_friends(client: ClientState<'_>) -> Result<Vec<Character>, cache::CacheError> {
-    let client_guard = client.client.read().await;
-    let client = client_guard.as_ref().expect("Too optimistic (get_friends)");
+async fn get_friends(
+    client: ClientState<'_>,
+    account: Option<String>,
+) -> Result<Vec<Character>, cache::CacheError> {
+    let client = client
+        .get(account.as_deref())
+        .await
+        .expect("Too optimistic (get_friends)");
 
     client.cache.get_friends().map(|v| v.into_owned())
 }
 
 #[tauri::command]
-async fn get_bookmarks(client: ClientState<'_>) -> Result<Vec<Character>, cache::CacheError> {
-    let client_guard = client.client.read().await;
-    let client = client_guard
-        .as_ref()
+async fn get_bookmarks(
+    client: ClientState<'_>,
+    account: Option<String>,
+) -> Result<Vec<Character>, cache::CacheError> {
+    let client = client
+        .get(account.as_deref())
+        .await
         .expect("Too optimistic (get_bookmarks)");
 
     client.cache.get_bookmarks().map(|v| v.into_owned())
@@ -92,111 +341,250 @@ async fn get_bookmarks(client: ClientState<'_>) -> Result<Vec<Character>, cache:
 #[tauri::command]
 async fn get_all_characters(
     client: ClientState<'_>,
-) -> Result<HashMap<Character, data::CharacterDataInner>, cache::CacheError> {
-    let client_guard = client.client.read().await;
-    let client = client_guard
-        .as_ref()
+    account: Option<String>,
+) -> Result<cache::CharacterSnapshot, cache::CacheError> {
+    let client = client
+        .get(account.as_deref())
+        .await
         .expect("Too optimistic (get_all_characters)");
 
-    client.cache.get_characters().map(|v| {
-        v.into_owned()
-            .drain(..)
-            .map(|v| {
-                (
-                    v.character,
-                    data::CharacterDataInner {
-                        status: v.status,
-                        gender: v.gender,
-                        status_message: v.status_message,
-                    },
-                )
-            })
-            .collect()
-    })
+    Ok(client.cache.character_snapshot())
 }
 
 #[tauri::command]
 async fn get_character(
     client: ClientState<'_>,
     character: Character,
+    account: Option<String>,
 ) -> Result<Option<CharacterData>, cache::CacheError> {
-    let client_guard = client.client.read().await;
-    let client = client_guard
-        .as_ref()
+    let client = client
+        .get(account.as_deref())
+        .await
         .expect("Too optimistic (get_character)");
 
     client.cache.get_character(&character)
 }
 
+#[tauri::command]
+async fn get_last_seen(
+    client: ClientState<'_>,
+    character: Character,
+    account: Option<String>,
+) -> Result<Option<cache::LastSeen>, ()> {
+    Ok(client
+        .get(account.as_deref())
+        .await
+        .and_then(|client| client.cache.get_last_seen(&character)))
+}
+
+#[tauri::command]
+async fn get_channel_list(
+    client: ClientState<'_>,
+    account: Option<String>,
+) -> Result<cache::ChannelLists, ()> {
+    let client = client
+        .get(account.as_deref())
+        .await
+        .expect("Too optimistic (get_channel_list)");
+
+    Ok(client.cache.channel_lists())
+}
+
 #[tauri::command]
 async fn get_channel(
     client: ClientState<'_>,
     channel: Channel,
-) -> Result<Option<ChannelData>, cache::CacheError> {
-    let client_guard = client.client.read().await;
-    let client = client_guard.as_ref().expect("Too optimistic (get_channel)");
+    account: Option<String>,
+) -> Result<Option<Arc<ChannelData>>, cache::CacheError> {
+    let client = client
+        .get(account.as_deref())
+        .await
+        .expect("Too optimistic (get_channel)");
 
-    client.cache.get_channel(&channel)
+    Ok(client.cache.channel_view(&channel))
 }
 
 #[tauri::command]
-async fn get_sessions(client: ClientState<'_>) -> Result<Vec<Character>, ()> {
-    let client_guard = client.client.read().await;
-    Ok(client_guard.as_ref().map_or(Vec::new(), |client| {
+async fn get_channel_members(
+    client: ClientState<'_>,
+    channel: Channel,
+    filter: Option<cache::MemberFilter>,
+    account: Option<String>,
+) -> Result<Option<Vec<cache::ChannelMember>>, ()> {
+    Ok(client.get(account.as_deref()).await.and_then(|client| {
         client
-            .get_sessions()
-            .drain(..)
-            .map(|v| v.character.clone())
-            .collect()
+            .cache
+            .get_channel_members(&channel, &filter.unwrap_or_default())
     }))
 }
 
+#[tauri::command]
+async fn get_channel_history(
+    client: ClientState<'_>,
+    channel: Channel,
+    account: Option<String>,
+) -> Result<Vec<cache::ChannelChange>, ()> {
+    Ok(client
+        .get(account.as_deref())
+        .await
+        .map_or(Vec::new(), |client| {
+            client.cache.get_channel_history(&channel)
+        }))
+}
+
+// Sessions across every account.
+#[tauri::command]
+async fn get_sessions(client: ClientState<'_>) -> Result<Vec<Character>, ()> {
+    Ok(client
+        .all()
+        .await
+        .iter()
+        .flat_map(|(_, client)| client.get_sessions())
+        .map(|v| v.character.clone())
+        .collect())
+}
+
 #[tauri::command]
 async fn get_recents(character: Character) -> Result<Vec<Character>, ()> {
     Ok(vec![]) // For now, this is not backed by anything.
                // Later, back this with a cache which is updated when messages are sent in DMs.
 }
 
+// PMs know their account from the own character; channels need telling (or it's the latest login).
+async fn for_message_channel(
+    client: &MaybeClient,
+    channel: &data::MessageChannel,
+    account: Option<&str>,
+) -> Option<Arc<HuskyClient>> {
+    match (channel, account) {
+        (data::MessageChannel::Character { own_character, .. }, None) => {
+            client.for_character(own_character).await
+        }
+        _ => client.get(account).await,
+    }
+}
+
 #[tauri::command]
 async fn get_messages(
     client: ClientState<'_>,
-    channel: MessageChannel,
-) -> Result<Vec<Message>, cache::CacheError> {
-    let client_guard = client.client.read().await;
-    let client = client_guard
-        .as_ref()
+    channel: data::MessageChannel,
+    account: Option<String>,
+) -> Result<Vec<cache::MessageRecord>, cache::CacheError> {
+    let client = for_message_channel(&client, &channel, account.as_deref())
+        .await
         .expect("Too optimistic (get_messages)");
 
-    client
-        .cache
-        .get_messages(&channel.into(), None, Some(80))
-        .map(|v| v.into_owned())
-}
-
-#[derive(serde::Deserialize, Debug)]
-#[serde(untagged)]
-// This is MessageChannel from f_chat_rs, but for a language without typed unions.
-// Maybe at a later time I'll go back to f_chat_rs and redesign how these are all described.
-enum MessageChannel {
-    Channel {
-        channel: Channel,
-    },
-    Character {
-        own_character: Character,
-        other_character: Character,
-    },
-}
-
-impl Into<f_data::MessageChannel> for MessageChannel {
-    fn into(self) -> f_data::MessageChannel {
-        match self {
-            MessageChannel::Channel { channel } => f_data::MessageChannel::Channel(channel),
-            MessageChannel::Character {
-                own_character,
-                other_character,
-            } => f_data::MessageChannel::PrivateMessage(own_character, other_character),
-        }
+    Ok(client.cache.get_message_records(&channel.into(), Some(80)))
+}
+
+#[tauri::command]
+async fn mark_read(
+    client: ClientState<'_>,
+    store: State<'_, store::Store>,
+    channel: data::MessageChannel,
+    up_to: Option<Timestamp>,
+    account: Option<String>,
+) -> Result<cache::UnreadData, store::StoreError> {
+    let client = for_message_channel(&client, &channel, account.as_deref())
+        .await
+        .expect("Too optimistic (mark_read)");
+
+    let unread = client.cache.mark_read(channel.into(), up_to);
+    store.save_read_markers(
+        client
+            .cache
+            .read_markers()
+            .into_iter()
+            .map(|(channel, last_read)| (channel.into(), last_read)),
+    )?;
+    Ok(unread)
+}
+
+#[tauri::command]
+async fn get_unread_summary(client: ClientState<'_>) -> Result<Vec<event::UnreadEvent>, ()> {
+    Ok(client
+        .all()
+        .await
+        .iter()
+        .flat_map(|(account, client)| {
+            client
+                .cache
+                .get_unread_summary()
+                .into_iter()
+                .map(move |(channel, data)| event::UnreadEvent {
+                    account: account.clone(),
+                    channel: channel.into(),
+                    data,
+                })
+        })
+        .collect())
+}
+
+// To see what's taking up memory. Sizes are estimates.
+#[tauri::command]
+async fn get_cache_stats(
+    client: ClientState<'_>,
+    account: Option<String>,
+) -> Result<BTreeMap<&'static str, cache::MapStats>, ()> {
+    Ok(client
+        .get(account.as_deref())
+        .await
+        .map(|client| client.cache.stats())
+        .unwrap_or_default())
+}
+
+#[tauri::command]
+async fn get_settings(
+    settings: State<'_, settings::SettingsStore>,
+) -> Result<settings::Settings, ()> {
+    Ok(settings.get())
+}
+
+#[tauri::command]
+async fn update_settings(
+    app: AppHandle,
+    client: ClientState<'_>,
+    settings: State<'_, settings::SettingsStore>,
+    patch: serde_json::Value,
+) -> Result<settings::Settings, store::StoreError> {
+    let updated = settings.update(patch)?;
+    for (account, client) in client.all().await {
+        client
+            .cache
+            .set_retain_offline(updated.global.retain_offline);
+        client.cache.set_retention(updated.global.retention());
+        // A smaller budget evicts now, and there may not be another message for a while.
+        event::log_evicted(&app, &account).await;
     }
+    // Settings are already saved by now; a window that missed the event can still ask.
+    if let Err(err) = app.emit_all("settings_changed", updated.clone()) {
+        eprintln!("Failed to emit event settings_changed: {}", err);
+    }
+    Ok(updated)
+}
+
+#[tauri::command]
+async fn get_watch_list(watch: State<'_, watch::Watch>) -> Result<watch::WatchList, ()> {
+    Ok(watch.get_list())
+}
+
+#[tauri::command]
+async fn set_watch_list(
+    watch: State<'_, watch::Watch>,
+    store: State<'_, store::Store>,
+    watch_list: watch::WatchList,
+) -> Result<(), store::StoreError> {
+    store.save(watch::WATCH_LIST, &watch_list)?;
+    watch.set_list(watch_list);
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_activity_feed(
+    watch: State<'_, watch::Watch>,
+    limit: Option<usize>,
+) -> Result<Vec<watch::WatchAlert>, ()> {
+    Ok(watch.get_feed(limit))
 }
 
 #[tauri::command]
@@ -206,9 +594,9 @@ async fn session_send_message(
     target: Target,
     message: String,
 ) -> AsyncVoid {
-    let client_guard = client.client.read().await;
-    let client = client_guard
-        .as_ref()
+    let client = client
+        .for_session(&session)
+        .await
         .expect("Too optimistic (session_send_message)");
 
     let session = client
@@ -227,10 +615,11 @@ async fn session_send_dice(
     session: Character,
     target: Target,
     dice: String,
-) -> AsyncVoid {
-    let client_guard = client.client.read().await;
-    let client = client_guard
-        .as_ref()
+) -> Result<(), dice::DiceError> {
+    dice::validate(&dice)?;
+    let client = client
+        .for_session(&session)
+        .await
         .expect("Too optimistic (session_send_dice)");
 
     let session = client
@@ -239,7 +628,7 @@ async fn session_send_dice(
     session
         .send_dice(target, dice)
         .await
-        .expect("Client error (session_send_dice)");
+        .map_err(|err| dice::DiceError::Client(format!("{:?}", err)))?;
     Ok(())
 }
 
@@ -249,9 +638,9 @@ async fn session_join_channel(
     session: Character,
     channel: Channel,
 ) -> AsyncVoid {
-    let client_guard = client.client.read().await;
-    let client = client_guard
-        .as_ref()
+    let client = client
+        .for_session(&session)
+        .await
         .expect("Too optimistic (join_channel)");
 
     let session = client
@@ -266,10 +655,8 @@ async fn session_join_channel(
 
 #[tokio::main]
 async fn main() {
-    let client = MaybeClient {
-        client: AsyncRwLock::new(None),
-    };
-    let (send, mut receive) = tokio::sync::mpsc::channel::<event::UpdateEvent>(8);
+    let client = MaybeClient::new();
+    let (send, receive) = tokio::sync::mpsc::channel::<event::AccountEvent>(event::EVENT_BUFFER);
 
     // This isn't necessary but I want to control the async runtime
     tauri::async_runtime::set(tokio::runtime::Handle::current());
@@ -277,32 +664,95 @@ async fn main() {
     tauri::Builder::default()
         .manage(client)
         .manage(send)
+        .manage(Endpoints::default())
         .setup(|app| {
+            let data_dir = app
+                .path_resolver()
+                .app_data_dir()
+                .expect("No app data directory");
+            let config_dir = app
+                .path_resolver()
+                .app_config_dir()
+                .expect("No app config directory");
+            app.manage(settings::SettingsStore::load(config_dir));
+
+            let vault = vault::Vault::new(data_dir.clone());
+            vault.unlock_without_passphrase();
+            app.manage(vault);
+
+            let store = store::Store::new(data_dir);
+            app.manage(watch::Watch::new(store.load(watch::WATCH_LIST)));
+            app.manage(drafts::Drafts::new());
+            app.manage(logs::Logs::new());
+            app.manage(schedule::Scheduler::new(store.load(schedule::SCHEDULED)));
+            app.manage(store);
+
             // Oh, and here's where I read off the receiver.
-            let handle = app.handle();
-            tokio::spawn(async move {
-                while let Some(event) = receive.recv().await {
-                    event::handle_event(&handle, event).await;
-                }
-            });
+            tokio::spawn(event::handle_events(app.handle(), receive));
+            tokio::spawn(schedule::run(app.handle()));
 
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             login,
+            login_saved,
+            get_accounts,
+            vault::vault_status,
+            vault::unlock_vault,
+            vault::set_vault_passphrase,
+            vault::lock_vault,
+            vault::forget_credentials,
             start_session,
             get_own_characters,
             get_friends,
             get_bookmarks,
             get_channel,
+            get_channel_list,
+            get_channel_members,
+            get_channel_history,
             get_character,
             get_all_characters,
+            get_last_seen,
             get_sessions,
             get_recents,
             get_messages,
+            mark_read,
+            get_unread_summary,
+            get_cache_stats,
+            export::export_logs,
+            import::import_logs,
+            get_settings,
+            update_settings,
+            get_watch_list,
+            set_watch_list,
+            get_activity_feed,
             session_send_message,
             session_send_dice,
-            session_join_channel
+            dice::get_roll_stats,
+            dice::roll_local,
+            dice::get_notes,
+            drafts::save_draft,
+            drafts::get_draft,
+            schedule::schedule_message,
+            schedule::cancel_scheduled,
+            schedule::get_scheduled,
+            session_join_channel,
+            moderation::channel_kick,
+            moderation::channel_ban,
+            moderation::channel_unban,
+            moderation::channel_timeout,
+            moderation::channel_list_bans,
+            moderation::channel_promote_op,
+            moderation::channel_demote_op,
+            moderation::channel_set_owner,
+            moderation::channel_set_mode,
+            moderation::channel_set_description,
+            moderation::channel_set_title,
+            room::create_private_channel,
+            room::set_channel_private,
+            room::invite_to_channel,
+            room::accept_invite,
+            room::get_channel_kind
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -0,0 +1,549 @@
+// A stand-in for the F-Chat server and F-List's ticket API, so tests can log in without a network.
+// It speaks just enough of the protocol for a client to identify, list and join channels, and
+// pass messages and status around. Anything else the client sends is recorded but ignored.
+//
+// Tests play the rest of the world through it: other characters logging in and out, talking in
+// channels, sending PMs.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+    time::{sleep, Instant},
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message as Frame};
+
+pub const TICKET: &str = "mock-ticket";
+
+#[derive(Debug, Clone, Default)]
+pub struct MockAccount {
+    pub username: String,
+    pub password: String,
+    pub characters: Vec<String>,
+    // (own character, friend)
+    pub friends: Vec<(String, String)>,
+    pub bookmarks: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct MockCharacter {
+    gender: String,
+    status: String,
+    status_message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+struct MockChannel {
+    title: String,
+    description: String,
+    official: bool,
+    members: Vec<String>,
+    ops: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    accounts: Vec<MockAccount>,
+    connections: HashMap<String, UnboundedSender<String>>,
+    online: HashMap<String, MockCharacter>,
+    channels: HashMap<String, MockChannel>,
+    received: Vec<String>,
+}
+
+pub struct MockServer {
+    pub api_url: String,
+    pub chat_url: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+fn frame(command: &str, payload: Value) -> String {
+    format!("{} {}", command, payload)
+}
+
+fn parse(frame: &str) -> (&str, Value) {
+    match frame.split_once(' ') {
+        Some((command, payload)) => (
+            command,
+            serde_json::from_str(payload).unwrap_or(Value::Null),
+        ),
+        None => (frame, Value::Null),
+    }
+}
+
+fn text(payload: &Value, key: &str) -> String {
+    payload[key].as_str().unwrap_or_default().to_string()
+}
+
+impl MockState {
+    fn send(&self, character: &str, frame: String) {
+        if let Some(connection) = self.connections.get(character) {
+            let _ = connection.send(frame);
+        }
+    }
+
+    fn broadcast(&self, frame: String) {
+        for connection in self.connections.values() {
+            let _ = connection.send(frame.clone());
+        }
+    }
+
+    fn broadcast_channel(&self, channel: &str, frame: String, except: Option<&str>) {
+        if let Some(channel) = self.channels.get(channel) {
+            for member in &channel.members {
+                if Some(member.as_str()) != except {
+                    self.send(member, frame.clone());
+                }
+            }
+        }
+    }
+
+    fn come_online(&mut self, character: &str, gender: &str) {
+        self.online.insert(
+            character.to_string(),
+            MockCharacter {
+                gender: gender.to_string(),
+                status: "online".to_string(),
+                status_message: String::new(),
+            },
+        );
+        self.broadcast(frame(
+            "NLN",
+            json!({ "identity": character, "gender": gender, "status": "online" }),
+        ));
+    }
+
+    fn go_offline(&mut self, character: &str) {
+        if self.online.remove(character).is_none() {
+            return;
+        }
+        self.connections.remove(character);
+        for channel in self.channels.values_mut() {
+            channel.members.retain(|v| v != character);
+        }
+        self.broadcast(frame("FLN", json!({ "character": character })));
+    }
+
+    fn identify(&mut self, payload: &Value, connection: UnboundedSender<String>) -> Option<String> {
+        let character = text(payload, "character");
+        let known = self
+            .accounts
+            .iter()
+            .any(|v| v.username == text(payload, "account") && v.characters.contains(&character));
+        if text(payload, "ticket") != TICKET || !known {
+            let _ = connection.send(frame(
+                "ERR",
+                json!({ "number": 4, "message": "Identification failed." }),
+            ));
+            return None;
+        }
+
+        self.connections.insert(character.clone(), connection);
+        self.send(&character, frame("IDN", json!({ "character": character })));
+        self.send(&character, frame("ADL", json!({ "ops": [] })));
+        let friends: Vec<&String> = self
+            .accounts
+            .iter()
+            .flat_map(|v| v.friends.iter())
+            .filter(|v| v.0 == character)
+            .map(|v| &v.1)
+            .collect();
+        self.send(&character, frame("FRL", json!({ "characters": friends })));
+        let online: Vec<Value> = self
+            .online
+            .iter()
+            .map(|(name, v)| json!([name, v.gender, v.status, v.status_message]))
+            .collect();
+        self.send(&character, frame("LIS", json!({ "characters": online })));
+        self.come_online(&character, "None");
+        Some(character)
+    }
+
+    fn handle(&mut self, character: &str, command: &str, payload: Value) {
+        match command {
+            "CHA" => {
+                let channels: Vec<Value> = self
+                    .channels
+                    .iter()
+                    .filter(|v| v.1.official)
+                    .map(|(name, v)| json!({ "name": name, "mode": "both", "characters": v.members.len() }))
+                    .collect();
+                self.send(character, frame("CHA", json!({ "channels": channels })));
+            }
+            "ORS" => {
+                let channels: Vec<Value> = self
+                    .channels
+                    .iter()
+                    .filter(|v| !v.1.official)
+                    .map(|(name, v)| json!({ "name": name, "title": v.title, "characters": v.members.len() }))
+                    .collect();
+                self.send(character, frame("ORS", json!({ "channels": channels })));
+            }
+            "JCH" => self.join(character, &text(&payload, "channel")),
+            "LCH" => {
+                let channel = text(&payload, "channel");
+                self.broadcast_channel(
+                    &channel,
+                    frame("LCH", json!({ "channel": channel, "character": character })),
+                    None,
+                );
+                if let Some(v) = self.channels.get_mut(&channel) {
+                    v.members.retain(|v| v != character);
+                }
+            }
+            "MSG" => {
+                let channel = text(&payload, "channel");
+                self.broadcast_channel(
+                    &channel,
+                    frame(
+                        "MSG",
+                        json!({ "channel": channel, "character": character, "message": payload["message"] }),
+                    ),
+                    Some(character),
+                );
+            }
+            "PRI" => self.send(
+                &text(&payload, "recipient"),
+                frame(
+                    "PRI",
+                    json!({ "character": character, "message": payload["message"] }),
+                ),
+            ),
+            "STA" => {
+                if let Some(v) = self.online.get_mut(character) {
+                    v.status = text(&payload, "status");
+                    v.status_message = text(&payload, "statusmsg");
+                }
+                self.broadcast(frame(
+                    "STA",
+                    json!({ "character": character, "status": payload["status"], "statusmsg": payload["statusmsg"] }),
+                ));
+            }
+            "PIN" => self.send(character, "PIN".to_string()),
+            _ => (),
+        }
+    }
+
+    fn join(&mut self, character: &str, channel: &str) {
+        let data = match self.channels.get_mut(channel) {
+            Some(data) => data,
+            None => {
+                self.send(
+                    character,
+                    frame("ERR", json!({ "number": 26, "message": "Could not locate the requested channel." })),
+                );
+                return;
+            }
+        };
+        if !data.members.iter().any(|v| v == character) {
+            data.members.push(character.to_string());
+        }
+        let data = data.clone();
+        self.broadcast_channel(
+            channel,
+            frame(
+                "JCH",
+                json!({ "channel": channel, "character": { "identity": character }, "title": data.title }),
+            ),
+            None,
+        );
+        // The rest of what a real server sends on joining, so the client knows who's there.
+        let users: Vec<Value> = data
+            .members
+            .iter()
+            .map(|v| json!({ "identity": v }))
+            .collect();
+        self.send(
+            character,
+            frame(
+                "ICH",
+                json!({ "channel": channel, "users": users, "mode": "both" }),
+            ),
+        );
+        self.send(
+            character,
+            frame(
+                "CDS",
+                json!({ "channel": channel, "description": data.description }),
+            ),
+        );
+        self.send(
+            character,
+            frame("COL", json!({ "channel": channel, "oplist": data.ops })),
+        );
+    }
+}
+
+impl MockServer {
+    pub async fn start(accounts: Vec<MockAccount>) -> MockServer {
+        let state = Arc::new(Mutex::new(MockState {
+            accounts,
+            ..Default::default()
+        }));
+
+        let api = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}/json/getApiTicket.php", api.local_addr().unwrap());
+        let api_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = api.accept().await {
+                tokio::spawn(serve_ticket(stream, api_state.clone()));
+            }
+        });
+
+        let chat = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let chat_url = format!("ws://{}/chat2", chat.local_addr().unwrap());
+        let chat_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = chat.accept().await {
+                tokio::spawn(serve_chat(stream, chat_state.clone()));
+            }
+        });
+
+        MockServer {
+            api_url,
+            chat_url,
+            state,
+        }
+    }
+
+    pub fn add_channel(&self, id: &str, title: &str, official: bool) {
+        self.state.lock().unwrap().channels.insert(
+            id.to_string(),
+            MockChannel {
+                title: title.to_string(),
+                official,
+                ..Default::default()
+            },
+        );
+    }
+
+    // Someone who isn't one of ours, logging in.
+    pub fn log_in(&self, character: &str, gender: &str) {
+        self.state.lock().unwrap().come_online(character, gender);
+    }
+
+    pub fn log_out(&self, character: &str) {
+        self.state.lock().unwrap().go_offline(character);
+    }
+
+    pub fn join(&self, character: &str, channel: &str) {
+        self.state.lock().unwrap().join(character, channel);
+    }
+
+    pub fn say(&self, character: &str, channel: &str, message: &str) {
+        self.state.lock().unwrap().handle(
+            character,
+            "MSG",
+            json!({ "channel": channel, "message": message }),
+        );
+    }
+
+    pub fn private_message(&self, character: &str, recipient: &str, message: &str) {
+        self.state.lock().unwrap().handle(
+            character,
+            "PRI",
+            json!({ "recipient": recipient, "message": message }),
+        );
+    }
+
+    pub fn set_status(&self, character: &str, status: &str, message: &str) {
+        self.state.lock().unwrap().handle(
+            character,
+            "STA",
+            json!({ "status": status, "statusmsg": message }),
+        );
+    }
+
+    // Everything the clients have sent with this command, in order.
+    pub fn received(&self, command: &str) -> Vec<Value> {
+        self.state
+            .lock()
+            .unwrap()
+            .received
+            .iter()
+            .map(|v| parse(v))
+            .filter(|v| v.0 == command)
+            .map(|v| v.1)
+            .collect()
+    }
+
+    pub async fn wait_for(&self, command: &str, count: usize) -> Vec<Value> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let received = self.received(command);
+            if received.len() >= count {
+                return received;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for {}",
+                command
+            );
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    pub fn is_online(&self, character: &str) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .connections
+            .contains_key(character)
+    }
+
+    pub async fn wait_online(&self, character: &str) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !self.is_online(character) {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for {} to connect",
+                character
+            );
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+async fn serve_chat(stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let socket = match accept_async(stream).await {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+    let (mut sink, mut source) = socket.split();
+    let (connection, mut outgoing) = unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(frame) = outgoing.recv().await {
+            if sink.send(Frame::Text(frame)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut identity: Option<String> = None;
+    while let Some(Ok(incoming)) = source.next().await {
+        let incoming = match incoming {
+            Frame::Text(text) => text,
+            Frame::Close(_) => break,
+            _ => continue,
+        };
+        let mut server = state.lock().unwrap();
+        server.received.push(incoming.clone());
+        let (command, payload) = parse(&incoming);
+        match &identity {
+            None if command == "IDN" => identity = server.identify(&payload, connection.clone()),
+            // Nothing else counts until the connection has identified.
+            None => (),
+            Some(character) => server.handle(character, command, payload),
+        }
+    }
+    if let Some(character) = identity {
+        state.lock().unwrap().go_offline(&character);
+    }
+}
+
+fn decode_form(body: &str) -> HashMap<String, String> {
+    fn decode(value: &str) -> String {
+        let mut out = Vec::new();
+        let bytes = value.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'+' => out.push(b' '),
+                b'%' if i + 2 < bytes.len() => {
+                    let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                    match hex.and_then(|v| u8::from_str_radix(v, 16).ok()) {
+                        Some(byte) => {
+                            out.push(byte);
+                            i += 2;
+                        }
+                        None => out.push(b'%'),
+                    }
+                }
+                byte => out.push(byte),
+            }
+            i += 1;
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
+    body.split('&')
+        .filter_map(|v| v.split_once('='))
+        .map(|(key, value)| (decode(key), decode(value)))
+        .collect()
+}
+
+// One request per connection, which is all a ticket request needs.
+async fn serve_ticket(mut stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let body = loop {
+        let read = match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => read,
+        };
+        request.extend_from_slice(&buffer[..read]);
+        let text = String::from_utf8_lossy(&request).into_owned();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length = head
+                .lines()
+                .filter_map(|v| v.split_once(':'))
+                .find(|v| v.0.eq_ignore_ascii_case("content-length"))
+                .and_then(|v| v.1.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if body.len() >= length {
+                break body.to_string();
+            }
+        }
+    };
+
+    let form = decode_form(&body);
+    let response = {
+        let state = state.lock().unwrap();
+        let account = state.accounts.iter().find(|v| {
+            Some(&v.username) == form.get("account") && Some(&v.password) == form.get("password")
+        });
+        match account {
+            Some(account) => {
+                let characters: HashMap<&String, usize> = account
+                    .characters
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| (v, i + 1))
+                    .collect();
+                let friends: Vec<Value> = account
+                    .friends
+                    .iter()
+                    .map(|v| json!({ "source_name": v.0, "dest_name": v.1 }))
+                    .collect();
+                let bookmarks: Vec<Value> = account
+                    .bookmarks
+                    .iter()
+                    .map(|v| json!({ "name": v }))
+                    .collect();
+                json!({
+                    "ticket": TICKET,
+                    "account_id": 1,
+                    "characters": characters,
+                    "default_character": characters.values().min(),
+                    "friends": friends,
+                    "bookmarks": bookmarks,
+                    "error": "",
+                })
+            }
+            None => json!({ "error": "Login failed." }),
+        }
+    };
+
+    let body = response.to_string();
+    let reply = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(reply.as_bytes()).await;
+}
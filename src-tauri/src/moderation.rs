@@ -0,0 +1,246 @@
+// Channel moderation, for sessions which are ops (or owners) somewhere.
+// Everything is checked against the cached op list first; the server would refuse anyway,
+// but it'd refuse with a SYS message rather than something the UI can act on.
+use std::sync::Arc;
+
+use f_chat_rs::{
+    data::{Channel, ChannelMode, Character},
+    protocol::ClientCommand,
+    session::Session,
+};
+use serde::Serialize;
+use tauri::State;
+use thiserror::Error;
+
+use crate::{cache::ChannelRole, HuskyClient, MaybeClient};
+
+// The server won't accept a timeout longer than this, in minutes.
+const MAX_TIMEOUT: u32 = 90;
+
+#[derive(Error, Debug, Serialize)]
+pub enum ModerationError {
+    #[error("Not logged in")]
+    NoClient,
+    #[error("No such session")]
+    NoSession,
+    #[error("Unknown channel")]
+    UnknownChannel,
+    #[error("Requires {required:?}, but session is {actual:?}")]
+    NotPermitted {
+        required: ChannelRole,
+        actual: ChannelRole,
+    },
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Not supported by the server: {0}")]
+    Unsupported(String),
+    #[error("Client error: {0}")]
+    Client(String),
+}
+
+// Finds the account the session is connected on, along with the session itself.
+pub(crate) async fn session_client(
+    client: &MaybeClient,
+    session: &Character,
+) -> Result<(Arc<HuskyClient>, Arc<Session>), ModerationError> {
+    match client.for_session(session).await {
+        Some(client) => {
+            let session = client
+                .get_session(session)
+                .ok_or(ModerationError::NoSession)?;
+            Ok((client, session))
+        }
+        None if client.all().await.is_empty() => Err(ModerationError::NoClient),
+        None => Err(ModerationError::NoSession),
+    }
+}
+
+pub(crate) async fn moderate(
+    client: &MaybeClient,
+    session: &Character,
+    channel: &Channel,
+    required: ChannelRole,
+    command: ClientCommand,
+) -> Result<(), ModerationError> {
+    let (client, session) = session_client(client, session).await?;
+
+    let actual = client
+        .cache
+        .channel_role(channel, &session.character)
+        .ok_or(ModerationError::UnknownChannel)?;
+    if actual < required {
+        return Err(ModerationError::NotPermitted { required, actual });
+    }
+    if let ClientCommand::ChannelSetMode { .. } | ClientCommand::ChannelSetDescription { .. } =
+        command
+    {
+        client
+            .cache
+            .expect_channel_change(channel.clone(), session.character);
+    }
+
+    session
+        .send_command(command)
+        .await
+        .map_err(|err| ModerationError::Client(format!("{:?}", err)))
+}
+
+#[tauri::command]
+pub async fn channel_kick(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    character: Character,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelKick {
+        channel: channel.clone(),
+        character,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Op, command).await
+}
+
+#[tauri::command]
+pub async fn channel_ban(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    character: Character,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelBan {
+        channel: channel.clone(),
+        character,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Op, command).await
+}
+
+#[tauri::command]
+pub async fn channel_unban(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    character: Character,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelUnban {
+        channel: channel.clone(),
+        character,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Op, command).await
+}
+
+#[tauri::command]
+pub async fn channel_timeout(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    character: Character,
+    minutes: u32,
+) -> Result<(), ModerationError> {
+    if minutes == 0 || minutes > MAX_TIMEOUT {
+        return Err(ModerationError::InvalidArgument(format!(
+            "Timeouts must be between 1 and {} minutes",
+            MAX_TIMEOUT
+        )));
+    }
+    let command = ClientCommand::ChannelTimeout {
+        channel: channel.clone(),
+        character,
+        length: minutes,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Op, command).await
+}
+
+// The ban list comes back as a system message in the channel, not as a response.
+#[tauri::command]
+pub async fn channel_list_bans(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelBanList {
+        channel: channel.clone(),
+    };
+    moderate(&client, &session, &channel, ChannelRole::Op, command).await
+}
+
+#[tauri::command]
+pub async fn channel_promote_op(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    character: Character,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelAddOp {
+        channel: channel.clone(),
+        character,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Owner, command).await
+}
+
+#[tauri::command]
+pub async fn channel_demote_op(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    character: Character,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelRemoveOp {
+        channel: channel.clone(),
+        character,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Owner, command).await
+}
+
+#[tauri::command]
+pub async fn channel_set_owner(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    character: Character,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelSetOwner {
+        channel: channel.clone(),
+        character,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Owner, command).await
+}
+
+#[tauri::command]
+pub async fn channel_set_mode(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    mode: ChannelMode,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelSetMode {
+        channel: channel.clone(),
+        mode,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Op, command).await
+}
+
+#[tauri::command]
+pub async fn channel_set_description(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    description: String,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelSetDescription {
+        channel: channel.clone(),
+        description,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Op, command).await
+}
+
+// F-Chat has no command for renaming a channel; titles are fixed when the room is made.
+// This is here so the UI has something to call, and gets a reason back instead of silence.
+#[tauri::command]
+pub async fn channel_set_title(
+    _session: Character,
+    _channel: Channel,
+    _title: String,
+) -> Result<(), ModerationError> {
+    Err(ModerationError::Unsupported(
+        "Channel titles can't be changed after creation".to_string(),
+    ))
+}
@@ -0,0 +1,93 @@
+// Private rooms: making them, opening and closing them, and invitations in and out.
+use f_chat_rs::{
+    data::{Channel, Character},
+    protocol::ClientCommand,
+};
+use tauri::State;
+
+use crate::{
+    cache::{ChannelKind, ChannelRole},
+    moderation::{moderate, session_client, ModerationError},
+    MaybeClient,
+};
+
+// The server replies by joining us to the new room, which comes through like any other join.
+#[tauri::command]
+pub async fn create_private_channel(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    title: String,
+) -> Result<(), ModerationError> {
+    let (_, session) = session_client(&client, &session).await?;
+
+    session
+        .send_command(ClientCommand::CreateChannel { title })
+        .await
+        .map_err(|err| ModerationError::Client(format!("{:?}", err)))
+}
+
+#[tauri::command]
+pub async fn set_channel_private(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    private: bool,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelSetStatus {
+        channel: channel.clone(),
+        public: !private,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Op, command).await?;
+
+    // The server doesn't tell us it worked, beyond the room turning up (or not) in the next list.
+    if let Some(client) = client.for_session(&session).await {
+        let kind = if private {
+            ChannelKind::Private
+        } else {
+            ChannelKind::Public
+        };
+        client.cache.set_channel_kind(&channel, kind);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn invite_to_channel(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+    character: Character,
+) -> Result<(), ModerationError> {
+    let command = ClientCommand::ChannelInvite {
+        channel: channel.clone(),
+        character,
+    };
+    moderate(&client, &session, &channel, ChannelRole::Member, command).await
+}
+
+// Accepting is just joining; the invite is what lets us in.
+#[tauri::command]
+pub async fn accept_invite(
+    client: State<'_, MaybeClient>,
+    session: Character,
+    channel: Channel,
+) -> Result<(), ModerationError> {
+    let (_, session) = session_client(&client, &session).await?;
+
+    session
+        .join_channel(channel)
+        .await
+        .map_err(|err| ModerationError::Client(format!("{:?}", err)))
+}
+
+#[tauri::command]
+pub async fn get_channel_kind(
+    client: State<'_, MaybeClient>,
+    account: Option<String>,
+    channel: Channel,
+) -> Result<Option<ChannelKind>, ()> {
+    Ok(client
+        .get(account.as_deref())
+        .await
+        .and_then(|client| client.cache.get_channel_kind(&channel)))
+}
@@ -0,0 +1,384 @@
+// Messages to send later, mostly for timed event announcements in rooms.
+// The queue is kept on disk, so anything still waiting when the app closes goes out after the next login,
+// as long as it's set to retry and that login is soon enough. Anything else that can't go out when it's
+// due is dropped, and the account is told once it's logged in (so there's someone to tell).
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration as StdDuration,
+};
+
+use chrono::{Duration, Utc};
+use f_chat_rs::{data::Character, protocol::Target, session::Session, util::Timestamp};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager, State};
+use thiserror::Error;
+use tokio::{
+    sync::Notify,
+    time::{sleep, Instant},
+};
+
+use crate::{
+    event,
+    store::{Store, StoreError},
+    MaybeClient,
+};
+
+pub const SCHEDULED: &str = "scheduled.json";
+// The server's msg_flood is half a second. A batch of announcements shouldn't get anywhere near it,
+// and whatever's being typed by hand at the same time needs some room too.
+const FLOOD_INTERVAL: StdDuration = StdDuration::from_millis(1500);
+const RETRY_INTERVAL_SECS: i64 = 30;
+// An announcement for an event that started an hour ago is more confusing than useful.
+const RETRY_WINDOW_SECS: i64 = 60 * 60;
+
+#[derive(Error, Debug, Serialize)]
+pub enum ScheduleError {
+    #[error("Nothing to send")]
+    Empty,
+    #[error("Not one of our characters")]
+    NotOwnCharacter,
+    #[error("No scheduled message {0}")]
+    NotFound(u64),
+    #[error("Session is offline")]
+    Offline,
+    #[error("Scheduled message {0} is being sent")]
+    Sending(u64),
+    #[error("Client error: {0}")]
+    Client(String),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledMessage {
+    pub id: u64,
+    pub session: Character,
+    pub target: Target,
+    pub text: String,
+    pub at: Timestamp,
+    // Keep trying while the session is offline (for a while), rather than dropping it.
+    pub retry: bool,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub next_attempt: Option<Timestamp>,
+    // Never read back, so a send that was cut short by a crash goes out again.
+    #[serde(skip_deserializing)]
+    pub sending: bool,
+}
+
+impl ScheduledMessage {
+    fn due(&self) -> Timestamp {
+        self.next_attempt.unwrap_or(self.at)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DroppedMessage {
+    #[serde(flatten)]
+    pub message: ScheduledMessage,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    queue: Mutex<Vec<ScheduledMessage>>,
+    next_id: AtomicU64,
+    // Poked whenever the queue changes, since the new head might be due sooner.
+    wake: Notify,
+    // Dropped while none of our accounts had the character, so nothing could be told about it yet.
+    // Only kept in memory; they're gone from the queue either way.
+    unannounced: Mutex<Vec<DroppedMessage>>,
+}
+
+impl Scheduler {
+    pub fn new(queue: Vec<ScheduledMessage>) -> Self {
+        let next_id = queue.iter().map(|v| v.id + 1).max().unwrap_or(1);
+        Scheduler {
+            queue: Mutex::new(queue),
+            next_id: AtomicU64::new(next_id),
+            wake: Notify::new(),
+            unannounced: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn list(&self) -> Vec<ScheduledMessage> {
+        let mut queue = self.queue.lock().unwrap().clone();
+        queue.sort_by_key(|v| (v.due(), v.id));
+        queue
+    }
+
+    pub fn schedule(
+        &self,
+        store: &Store,
+        session: Character,
+        target: Target,
+        text: String,
+        at: Timestamp,
+        retry: bool,
+    ) -> Result<ScheduledMessage, StoreError> {
+        let message = ScheduledMessage {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            session,
+            target,
+            text,
+            at,
+            retry,
+            attempts: 0,
+            next_attempt: None,
+            sending: false,
+        };
+        let mut queue = self.queue.lock().unwrap();
+        queue.push(message.clone());
+        store.save(SCHEDULED, &*queue)?;
+        self.wake.notify_one();
+        Ok(message)
+    }
+
+    pub fn cancel(&self, store: &Store, id: u64) -> Result<ScheduledMessage, ScheduleError> {
+        let mut queue = self.queue.lock().unwrap();
+        let index = queue
+            .iter()
+            .position(|v| v.id == id)
+            .ok_or(ScheduleError::NotFound(id))?;
+        if queue[index].sending {
+            return Err(ScheduleError::Sending(id));
+        }
+        let message = queue.remove(index);
+        store.save(SCHEDULED, &*queue)?;
+        self.wake.notify_one();
+        Ok(message)
+    }
+
+    // A new session might have something waiting on it.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    fn next_due(&self) -> Option<Timestamp> {
+        self.queue
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|v| !v.sending)
+            .map(|v| v.due())
+            .min()
+    }
+
+    fn due(&self, now: Timestamp) -> Vec<ScheduledMessage> {
+        let mut due: Vec<_> = self
+            .queue
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|v| !v.sending && v.due() <= now)
+            .cloned()
+            .collect();
+        due.sort_by_key(|v| (v.due(), v.id));
+        due
+    }
+
+    // None if it was cancelled while waiting its turn. It stays in the queue (and the file) while
+    // it's sent, so a crash mid-send usually means sending twice rather than not at all.
+    fn begin(&self, id: u64) -> Option<ScheduledMessage> {
+        let mut queue = self.queue.lock().unwrap();
+        let message = queue.iter_mut().find(|v| v.id == id)?;
+        message.sending = true;
+        Some(message.clone())
+    }
+
+    // With a retry, it goes back in the queue as that; otherwise it's done with.
+    // Either way, one cancelled in the meantime stays cancelled.
+    fn finish(
+        &self,
+        store: &Store,
+        id: u64,
+        retry: Option<ScheduledMessage>,
+    ) -> Result<(), StoreError> {
+        let mut queue = self.queue.lock().unwrap();
+        match retry {
+            Some(retry) => {
+                if let Some(message) = queue.iter_mut().find(|v| v.id == id) {
+                    *message = retry;
+                }
+            }
+            None => queue.retain(|v| v.id != id),
+        }
+        store.save(SCHEDULED, &*queue)
+    }
+}
+
+// A session that's there to send on, and the account it's on.
+type Online = (String, Arc<Session>);
+
+async fn online_sessions(client: &MaybeClient) -> HashMap<Character, Online> {
+    let mut online = HashMap::new();
+    for (account, client) in client.all().await {
+        for session in client.get_sessions() {
+            online.insert(session.character, (account.clone(), session));
+        }
+    }
+    online
+}
+
+// Told to whichever account has the character, or held on to until one logs in.
+async fn report_dropped(
+    app: &AppHandle,
+    scheduler: &Scheduler,
+    client: &MaybeClient,
+    dropped: DroppedMessage,
+) {
+    let account = client
+        .all()
+        .await
+        .into_iter()
+        .find(|v| v.1.own_characters.contains(&dropped.message.session))
+        .map(|v| v.0);
+    match account {
+        Some(account) => event::emit(app, &account, "scheduled_dropped", dropped),
+        None => scheduler.unannounced.lock().unwrap().push(dropped),
+    }
+}
+
+// Retries go again after a while if there's still time, anything else is dropped.
+async fn failed(
+    app: &AppHandle,
+    scheduler: &Scheduler,
+    client: &MaybeClient,
+    mut message: ScheduledMessage,
+    err: ScheduleError,
+) -> Option<ScheduledMessage> {
+    let now = Utc::now();
+    if message.retry && now - message.at < Duration::seconds(RETRY_WINDOW_SECS) {
+        message.next_attempt = Some(now + Duration::seconds(RETRY_INTERVAL_SECS));
+        return Some(message);
+    }
+    let reason = err.to_string();
+    report_dropped(app, scheduler, client, DroppedMessage { message, reason }).await;
+    None
+}
+
+pub async fn run(app: AppHandle) {
+    let scheduler = app.state::<Scheduler>();
+    let client = app.state::<MaybeClient>();
+    let store = app.state::<Store>();
+    let mut last_sent: Option<Instant> = None;
+    loop {
+        let held = std::mem::take(&mut *scheduler.unannounced.lock().unwrap());
+        for dropped in held {
+            report_dropped(&app, &scheduler, &client, dropped).await;
+        }
+
+        let due = scheduler.due(Utc::now());
+        if due.is_empty() {
+            let mut wait = scheduler
+                .next_due()
+                .map(|at| (at - Utc::now()).to_std().unwrap_or_default());
+            // Nothing wakes this up on login, so anything held for one is checked on every so often.
+            if !scheduler.unannounced.lock().unwrap().is_empty() {
+                let check = StdDuration::from_secs(RETRY_INTERVAL_SECS as u64);
+                wait = Some(wait.map_or(check, |v| v.min(check)));
+            }
+            match wait {
+                Some(wait) => {
+                    tokio::select! {
+                        _ = sleep(wait) => {}
+                        _ = scheduler.wake.notified() => {}
+                    }
+                }
+                None => scheduler.wake.notified().await,
+            }
+            continue;
+        }
+
+        let online = online_sessions(&client).await;
+        for message in due {
+            let id = message.id;
+            let (account, session) = match online.get(&message.session) {
+                Some(online) => online.clone(),
+                None => {
+                    let retry =
+                        failed(&app, &scheduler, &client, message, ScheduleError::Offline).await;
+                    if let Err(err) = scheduler.finish(&store, id, retry) {
+                        eprintln!("Failed to save scheduled messages: {}", err);
+                    }
+                    continue;
+                }
+            };
+            if let Some(last_sent) = last_sent {
+                let since = last_sent.elapsed();
+                if since < FLOOD_INTERVAL {
+                    sleep(FLOOD_INTERVAL - since).await;
+                }
+            }
+            let mut message = match scheduler.begin(id) {
+                Some(message) => message,
+                None => continue,
+            };
+            let result = session
+                .send_message(message.target.clone(), message.text.clone())
+                .await
+                .map_err(|err| ScheduleError::Client(format!("{:?}", err)));
+            message.sending = false;
+            message.attempts += 1;
+            let retry = match result {
+                Ok(()) => {
+                    last_sent = Some(Instant::now());
+                    event::emit(&app, &account, "scheduled_sent", message);
+                    None
+                }
+                Err(err) => failed(&app, &scheduler, &client, message, err).await,
+            };
+            if let Err(err) = scheduler.finish(&store, id, retry) {
+                eprintln!("Failed to save scheduled messages: {}", err);
+            }
+        }
+    }
+}
+
+// `at` in the past just means as soon as possible.
+#[tauri::command]
+pub async fn schedule_message(
+    client: State<'_, MaybeClient>,
+    scheduler: State<'_, Scheduler>,
+    store: State<'_, Store>,
+    session: Character,
+    target: Target,
+    text: String,
+    at: Timestamp,
+    retry: Option<bool>,
+) -> Result<ScheduledMessage, ScheduleError> {
+    if text.trim().is_empty() {
+        return Err(ScheduleError::Empty);
+    }
+    // It doesn't have to be online now, but it does have to be ours.
+    if client.for_character(&session).await.is_none() {
+        return Err(ScheduleError::NotOwnCharacter);
+    }
+    Ok(scheduler.schedule(&store, session, target, text, at, retry.unwrap_or(true))?)
+}
+
+#[tauri::command]
+pub async fn cancel_scheduled(
+    scheduler: State<'_, Scheduler>,
+    store: State<'_, Store>,
+    id: u64,
+) -> Result<ScheduledMessage, ScheduleError> {
+    scheduler.cancel(&store, id)
+}
+
+#[tauri::command]
+pub async fn get_scheduled(
+    scheduler: State<'_, Scheduler>,
+    session: Option<Character>,
+) -> Result<Vec<ScheduledMessage>, ()> {
+    Ok(scheduler
+        .list()
+        .into_iter()
+        .filter(|v| session.map_or(true, |session| v.session == session))
+        .collect())
+}
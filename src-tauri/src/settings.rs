@@ -0,0 +1,176 @@
+use std::{collections::HashMap, fs, path::PathBuf, sync::RwLock};
+
+use f_chat_rs::data::Character;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    cache::Retention,
+    store::{write_atomic, StoreError},
+};
+
+pub const SETTINGS: &str = "settings.json";
+pub const SETTINGS_VERSION: u32 = 1;
+
+// Each of these takes the settings from version N to N+1, as raw JSON.
+// Add to the end when the layout changes, and bump SETTINGS_VERSION to match.
+const MIGRATIONS: &[fn(&mut Value)] = &[migrate_v0];
+
+// Version 0 is anything written before settings were versioned; there's nothing to carry over.
+fn migrate_v0(_settings: &mut Value) {}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u32,
+    pub global: GlobalSettings,
+    // Keyed by account name.
+    pub accounts: HashMap<String, AccountSettings>,
+    // Keyed by own character.
+    pub characters: HashMap<Character, CharacterSettings>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            version: SETTINGS_VERSION,
+            global: Default::default(),
+            accounts: Default::default(),
+            characters: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GlobalSettings {
+    // Keep offline friends and bookmarks in the character list.
+    pub retain_offline: bool,
+    pub last_account: Option<String>,
+    // Messages held in memory per conversation, and in total (in MiB, roughly).
+    pub message_limit: usize,
+    pub message_budget: usize,
+    // Whether messages that no longer fit go to the logs, or are dropped.
+    pub log_evicted: bool,
+}
+
+impl Default for GlobalSettings {
+    fn default() -> Self {
+        let retention = Retention::default();
+        GlobalSettings {
+            retain_offline: true,
+            last_account: None,
+            message_limit: retention.per_channel,
+            message_budget: retention.budget / (1024 * 1024),
+            log_evicted: retention.log_evicted,
+        }
+    }
+}
+
+impl GlobalSettings {
+    pub fn retention(&self) -> Retention {
+        Retention {
+            per_channel: self.message_limit.max(1),
+            budget: self.message_budget.max(1) * 1024 * 1024,
+            log_evicted: self.log_evicted,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccountSettings {
+    pub auto_login: bool,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CharacterSettings {
+    // Extra words (beyond the character's own name) that count as a mention.
+    pub highlight_words: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct SettingsStore {
+    path: PathBuf,
+    settings: RwLock<Settings>,
+}
+
+impl SettingsStore {
+    // A settings file we can't make sense of is set aside rather than overwritten,
+    // so that a bad migration doesn't quietly throw away someone's configuration.
+    pub fn load(dir: PathBuf) -> Self {
+        let path = dir.join(SETTINGS);
+        let settings = match fs::read(&path) {
+            Ok(raw) => match serde_json::from_slice(&raw).map(migrate) {
+                Ok(Some(settings)) => settings,
+                _ => {
+                    eprintln!("Unreadable settings, starting from defaults");
+                    let _ = fs::rename(&path, path.with_extension("json.bak"));
+                    Settings::default()
+                }
+            },
+            Err(_) => Settings::default(),
+        };
+        SettingsStore {
+            path,
+            settings: RwLock::new(settings),
+        }
+    }
+
+    pub fn get(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    // The patch is merged into the current settings, so callers only send what they change.
+    // e.g. `{ "characters": { "Name": { "highlight_words": ["husky"] } } }`
+    pub fn update(&self, patch: Value) -> Result<Settings, StoreError> {
+        let mut settings = self.settings.write().unwrap();
+        let mut value = serde_json::to_value(&*settings)?;
+        merge(&mut value, patch);
+        let mut updated: Settings = serde_json::from_value(value)?;
+        updated.version = SETTINGS_VERSION;
+
+        write_atomic(&self.path, &serde_json::to_vec_pretty(&updated)?)?;
+        *settings = updated.clone();
+        Ok(updated)
+    }
+
+    pub fn character(&self, character: &Character) -> CharacterSettings {
+        self.settings
+            .read()
+            .unwrap()
+            .characters
+            .get(character)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+fn migrate(mut value: Value) -> Option<Settings> {
+    if !value.is_object() {
+        return None;
+    }
+    let version = value.get("version").and_then(Value::as_u64).unwrap_or(0) as usize;
+    if version > MIGRATIONS.len() {
+        // Written by a newer Husky. Don't guess.
+        return None;
+    }
+    for migration in &MIGRATIONS[version..] {
+        migration(&mut value);
+    }
+    value["version"] = SETTINGS_VERSION.into();
+    serde_json::from_value(value).ok()
+}
+
+// Objects are merged key by key, anything else is replaced outright.
+fn merge(target: &mut Value, patch: Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, patch) => *target = patch,
+    }
+}
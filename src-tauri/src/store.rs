@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use f_chat_rs::util::Timestamp;
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::data;
+
+pub const READ_MARKERS: &str = "read_markers.json";
+
+// Anything which has to outlive the process goes in here, as a JSON file in the app data dir.
+// This isn't a database and shouldn't be treated as one. Write whole files, read whole files.
+#[derive(Debug)]
+pub struct Store {
+    dir: PathBuf,
+    // Read markers are merged into the file rather than written over it, from more than one place.
+    markers: Mutex<()>,
+}
+
+#[derive(Error, Debug, Serialize)]
+pub enum StoreError {
+    #[error("IO error: {0}")]
+    Io(String),
+    #[error("Format error: {0}")]
+    Format(String),
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(err: std::io::Error) -> Self {
+        StoreError::Io(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(err: serde_json::Error) -> Self {
+        StoreError::Format(err.to_string())
+    }
+}
+
+impl Store {
+    pub fn new(dir: PathBuf) -> Self {
+        Store {
+            dir,
+            markers: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    // Missing or unreadable files are treated as empty rather than as errors.
+    // Losing a read marker is annoying, refusing to start over it is worse.
+    pub fn load<T: DeserializeOwned + Default>(&self, name: &str) -> T {
+        fs::read(self.path(name))
+            .ok()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .unwrap_or_default()
+    }
+
+    // For files that are about to be added to and written back. A missing file is still empty,
+    // but one that can't be read is an error, so it isn't written over with only the new part.
+    pub fn load_existing<T: DeserializeOwned + Default>(
+        &self,
+        name: &str,
+    ) -> Result<T, StoreError> {
+        match fs::read(self.path(name)) {
+            Ok(raw) => Ok(serde_json::from_slice(&raw)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn save<T: Serialize>(&self, name: &str, value: &T) -> Result<(), StoreError> {
+        write_atomic(&self.path(name), &serde_json::to_vec(value)?)
+    }
+
+    // Every account shares the one marker file, so one account's markers are merged in, not written over.
+    // A marker only ever moves forward.
+    pub fn save_read_markers(
+        &self,
+        markers: impl IntoIterator<Item = (data::MessageChannel, Timestamp)>,
+    ) -> Result<(), StoreError> {
+        let _writing = self.markers.lock().unwrap();
+        let mut saved: HashMap<data::MessageChannel, Timestamp> = self
+            .load::<Vec<(data::MessageChannel, Timestamp)>>(READ_MARKERS)
+            .into_iter()
+            .collect();
+        for (channel, last_read) in markers {
+            let marker = saved.entry(channel).or_insert(last_read);
+            *marker = (*marker).max(last_read);
+        }
+        self.save(READ_MARKERS, &saved.into_iter().collect::<Vec<_>>())
+    }
+}
+
+// Write to a sibling file and rename over the original, so a crash mid-write can't eat the old data.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), StoreError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,464 @@
+// End to end: a real client logs in to the mock server, and everything it hears goes through the
+// real event loop. Scenarios play out on the server side and check what reaches the frontend.
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use f_chat_rs::{
+    cache::Cache,
+    data::{Channel, Character, Message, MessageChannel, MessageContent},
+    protocol::Target,
+};
+use serde_json::Value;
+use tauri::{
+    test::{mock_app, MockRuntime},
+    App, Manager,
+};
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver},
+    time::timeout,
+};
+
+use crate::{
+    cache::MemberFilter,
+    connect_account, data,
+    event::{self, AccountEvent, EventInvite, UpdateEvent},
+    logs::Logs,
+    mock_server::{MockAccount, MockServer, TICKET},
+    settings::SettingsStore,
+    store::Store,
+    watch::Watch,
+    Endpoints, MaybeClient,
+};
+
+// Everything the frontend listens for.
+const EVENTS: &[&str] = &[
+    "message",
+    "invite",
+    "unread_changed",
+    "update_bookmarks",
+    "update_friends",
+    "update_channels",
+    "watch_alert",
+    "character_online",
+    "character_offline",
+    "channel_changed",
+    "channel_member_joined",
+    "channel_member_left",
+    "characters_delta",
+    "characters_resync",
+];
+const WAIT: Duration = Duration::from_secs(5);
+
+fn character(name: &str) -> Character {
+    data::from_name(name).unwrap()
+}
+
+fn channel(name: &str) -> Channel {
+    data::from_name(name).unwrap()
+}
+
+fn account() -> MockAccount {
+    MockAccount {
+        username: "husky".to_string(),
+        password: "hunter2".to_string(),
+        characters: vec!["Sled Dog".to_string(), "Malamute".to_string()],
+        friends: vec![("Sled Dog".to_string(), "Arctic Fox".to_string())],
+        bookmarks: vec!["Snow Owl".to_string()],
+    }
+}
+
+// Each test gets its own data directory, so nothing leaks between them.
+fn scratch_dir() -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "husky-test-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+struct Harness {
+    server: MockServer,
+    app: App<MockRuntime>,
+    emitter: mpsc::Sender<AccountEvent>,
+    events: UnboundedReceiver<(String, Value)>,
+}
+
+impl Harness {
+    async fn new(accounts: Vec<MockAccount>) -> Harness {
+        let server = MockServer::start(accounts).await;
+        let dir = scratch_dir();
+
+        let app = mock_app();
+        app.manage(MaybeClient::new());
+        app.manage(SettingsStore::load(dir.clone()));
+        app.manage(Watch::default());
+        app.manage(Store::new(dir));
+        app.manage(Logs::new());
+        app.manage(Endpoints {
+            api: server.api_url.clone(),
+            chat: server.chat_url.clone(),
+        });
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        for name in EVENTS {
+            let sender = sender.clone();
+            app.listen_global(*name, move |event| {
+                let payload = event
+                    .payload()
+                    .and_then(|v| serde_json::from_str(v).ok())
+                    .unwrap_or(Value::Null);
+                let _ = sender.send((name.to_string(), payload));
+            });
+        }
+
+        let (emitter, updates) = mpsc::channel(event::EVENT_BUFFER);
+        tokio::spawn(event::handle_events(app.handle(), updates));
+
+        Harness {
+            server,
+            app,
+            emitter,
+            events: receiver,
+        }
+    }
+
+    async fn login(&self, username: &str, password: &str) -> Result<String, String> {
+        connect_account(
+            &self.app.state::<MaybeClient>(),
+            &self.emitter,
+            &self.app.state::<Store>(),
+            &self.app.state::<SettingsStore>(),
+            &self.app.state::<Endpoints>(),
+            username.to_string(),
+            password.to_string(),
+            None,
+        )
+        .await
+    }
+
+    async fn start_session(&self, name: &str) {
+        let client = self
+            .app
+            .state::<MaybeClient>()
+            .for_character(&character(name))
+            .await
+            .expect("No account owns this character");
+        client.connect(character(name)).await.unwrap();
+        self.server.wait_online(name).await;
+    }
+
+    async fn join(&self, session: &str, name: &str) {
+        let client = self
+            .app
+            .state::<MaybeClient>()
+            .for_session(&character(session))
+            .await
+            .unwrap();
+        let session = client.get_session(&character(session)).unwrap();
+        session.join_channel(channel(name)).await.unwrap();
+        self.server.wait_for("JCH", 1).await;
+    }
+
+    // Waits for the named event, passing over anything else. Returns the payload.
+    async fn expect(&mut self, name: &str) -> Value {
+        timeout(WAIT, async {
+            loop {
+                match self.events.recv().await {
+                    Some((event, payload)) if event == name => return payload,
+                    Some(_) => continue,
+                    None => panic!("Event loop stopped"),
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Timed out waiting for {}", name))
+    }
+
+    // Like expect, but keeps going until the payload passes the check.
+    async fn expect_where(&mut self, name: &str, check: impl Fn(&Value) -> bool) -> Value {
+        timeout(WAIT, async {
+            loop {
+                let payload = self.expect(name).await;
+                if check(&payload) {
+                    return payload;
+                }
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Timed out waiting for a matching {}", name))
+    }
+}
+
+fn mentions(payload: &Value, name: &str) -> bool {
+    payload.to_string().contains(name)
+}
+
+#[tokio::test]
+async fn login_gets_a_ticket_and_characters() {
+    let harness = Harness::new(vec![account()]).await;
+    let ticket = harness.login("husky", "hunter2").await.unwrap();
+    assert_eq!(ticket, TICKET);
+
+    let client = harness
+        .app
+        .state::<MaybeClient>()
+        .get(Some("husky"))
+        .await
+        .unwrap();
+    assert!(client.own_characters.contains(&character("Sled Dog")));
+    assert!(client.own_characters.contains(&character("Malamute")));
+    assert!(client.cache.is_bookmark(&character("Snow Owl")));
+    assert_eq!(
+        harness
+            .app
+            .state::<SettingsStore>()
+            .get()
+            .global
+            .last_account,
+        Some("husky".to_string())
+    );
+}
+
+#[tokio::test]
+async fn login_with_the_wrong_password_fails() {
+    let harness = Harness::new(vec![account()]).await;
+    assert!(harness.login("husky", "wrong").await.is_err());
+    assert!(harness.app.state::<MaybeClient>().all().await.is_empty());
+}
+
+#[tokio::test]
+async fn session_identifies_and_loads_online_characters() {
+    let mut harness = Harness::new(vec![account()]).await;
+    harness.server.log_in("Arctic Fox", "Female");
+    harness.login("husky", "hunter2").await.unwrap();
+    harness.start_session("Sled Dog").await;
+
+    let identify = harness.server.wait_for("IDN", 1).await;
+    assert_eq!(identify[0]["character"], "Sled Dog");
+    assert_eq!(identify[0]["ticket"], TICKET);
+
+    // The initial list comes through as one batch, not a character at a time.
+    let delta = harness.expect("characters_delta").await;
+    assert!(mentions(&delta["data"], "Arctic Fox"));
+    assert_eq!(delta["account"], "husky");
+
+    let client = harness.app.state::<MaybeClient>().get(None).await.unwrap();
+    assert!(client
+        .cache
+        .get_character(&character("Arctic Fox"))
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+async fn friends_coming_and_going_are_announced() {
+    let mut harness = Harness::new(vec![account()]).await;
+    harness.login("husky", "hunter2").await.unwrap();
+    harness.start_session("Sled Dog").await;
+
+    harness.server.log_in("Arctic Fox", "Female");
+    let online = harness
+        .expect_where("character_online", |v| mentions(v, "Arctic Fox"))
+        .await;
+    assert_eq!(online["account"], "husky");
+    let alert = harness.expect("watch_alert").await;
+    assert_eq!(alert["data"][0]["friend"], true);
+
+    harness.server.log_out("Arctic Fox");
+    harness
+        .expect_where("character_offline", |v| mentions(v, "Arctic Fox"))
+        .await;
+    let client = harness.app.state::<MaybeClient>().get(None).await.unwrap();
+    assert!(client
+        .cache
+        .get_last_seen(&character("Arctic Fox"))
+        .map_or(false, |v| !v.online));
+}
+
+#[tokio::test]
+async fn status_changes_reach_the_cache() {
+    let mut harness = Harness::new(vec![account()]).await;
+    harness.server.log_in("Snow Owl", "Male");
+    harness.login("husky", "hunter2").await.unwrap();
+    harness.start_session("Sled Dog").await;
+
+    harness.server.set_status("Snow Owl", "busy", "Hunting");
+    harness
+        .expect_where("characters_delta", |v| mentions(v, "Hunting"))
+        .await;
+    let client = harness.app.state::<MaybeClient>().get(None).await.unwrap();
+    let data = client
+        .cache
+        .get_character(&character("Snow Owl"))
+        .unwrap()
+        .unwrap();
+    assert_eq!(data.status_message, "Hunting");
+}
+
+#[tokio::test]
+async fn channel_messages_count_as_unread_and_mentions() {
+    let mut harness = Harness::new(vec![account()]).await;
+    harness.server.add_channel("Frontpage", "Frontpage", true);
+    harness.server.log_in("Arctic Fox", "Female");
+    harness.server.join("Arctic Fox", "Frontpage");
+    harness.login("husky", "hunter2").await.unwrap();
+    harness.start_session("Sled Dog").await;
+    harness.join("Sled Dog", "Frontpage").await;
+
+    harness
+        .server
+        .say("Arctic Fox", "Frontpage", "Nobody in particular");
+    // The unread count goes out ahead of the message itself, and expect passes over anything else.
+    let unread = harness.expect("unread_changed").await;
+    assert_eq!(unread["data"]["unread"], 1);
+    assert_eq!(unread["data"]["mentions"], 0);
+    let message = harness.expect("message").await;
+    assert_eq!(message["account"], "husky");
+
+    harness
+        .server
+        .say("Arctic Fox", "Frontpage", "Hello, sled dog!");
+    let unread = harness
+        .expect_where("unread_changed", |v| v["data"]["unread"] == 2)
+        .await;
+    assert_eq!(unread["data"]["mentions"], 1);
+
+    // Replies go out to the channel.
+    let client = harness.app.state::<MaybeClient>().get(None).await.unwrap();
+    client
+        .get_session(&character("Sled Dog"))
+        .unwrap()
+        .send_message(Target::Channel(channel("Frontpage")), "Hi!".to_string())
+        .await
+        .unwrap();
+    let sent = harness.server.wait_for("MSG", 1).await;
+    assert_eq!(sent[0]["message"], "Hi!");
+}
+
+#[tokio::test]
+async fn private_messages_are_always_mentions() {
+    let mut harness = Harness::new(vec![account()]).await;
+    harness.server.log_in("Arctic Fox", "Female");
+    harness.login("husky", "hunter2").await.unwrap();
+    harness.start_session("Sled Dog").await;
+
+    harness
+        .server
+        .private_message("Arctic Fox", "Sled Dog", "psst");
+    let unread = harness.expect("unread_changed").await;
+    assert_eq!(unread["data"]["channel"]["other_character"], "Arctic Fox");
+    assert_eq!(unread["data"]["mentions"], 1);
+
+    let client = harness.app.state::<MaybeClient>().get(None).await.unwrap();
+    let messages = client
+        .cache
+        .get_messages(
+            &MessageChannel::PrivateMessage(character("Sled Dog"), character("Arctic Fox")),
+            None,
+            None,
+        )
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+}
+
+#[tokio::test]
+async fn members_joining_and_leaving_are_announced() {
+    let mut harness = Harness::new(vec![account()]).await;
+    harness.server.add_channel("ADH-0123", "Den", false);
+    harness.server.log_in("Arctic Fox", "Female");
+    harness.login("husky", "hunter2").await.unwrap();
+    harness.start_session("Sled Dog").await;
+    harness.join("Sled Dog", "ADH-0123").await;
+
+    harness.server.join("Arctic Fox", "ADH-0123");
+    harness
+        .expect_where("channel_member_joined", |v| mentions(v, "Arctic Fox"))
+        .await;
+    let client = harness.app.state::<MaybeClient>().get(None).await.unwrap();
+    let members = client
+        .cache
+        .get_channel_members(&channel("ADH-0123"), &MemberFilter::default())
+        .unwrap();
+    assert!(members
+        .iter()
+        .any(|v| v.character == character("Arctic Fox")));
+
+    // Logging off leaves every channel.
+    harness.server.log_out("Arctic Fox");
+    harness
+        .expect_where("channel_member_left", |v| mentions(v, "Arctic Fox"))
+        .await;
+}
+
+#[tokio::test]
+async fn events_are_tagged_with_their_account() {
+    let other = MockAccount {
+        username: "wolf".to_string(),
+        password: "moon".to_string(),
+        characters: vec!["Grey Wolf".to_string()],
+        ..Default::default()
+    };
+    let mut harness = Harness::new(vec![account(), other]).await;
+    harness.server.log_in("Arctic Fox", "Female");
+    harness.login("husky", "hunter2").await.unwrap();
+    harness.login("wolf", "moon").await.unwrap();
+    harness.start_session("Grey Wolf").await;
+
+    harness
+        .server
+        .private_message("Arctic Fox", "Grey Wolf", "Awoo");
+    let message = harness.expect("message").await;
+    assert_eq!(message["account"], "wolf");
+    assert_eq!(harness.app.state::<MaybeClient>().all().await.len(), 2);
+}
+
+// handle_event on its own: messages and invites go straight out, the rest waits for the batch.
+#[tokio::test]
+async fn handle_event_emits_messages_and_invites_immediately() {
+    let mut harness = Harness::new(vec![account()]).await;
+    harness.login("husky", "hunter2").await.unwrap();
+    let mut batches = HashMap::new();
+
+    let message = Message {
+        character: character("Arctic Fox"),
+        content: MessageContent::Message("Hello".to_string()),
+        timestamp: chrono::Utc::now(),
+    };
+    let update = AccountEvent {
+        account: "husky".to_string(),
+        event: UpdateEvent::Message(
+            MessageChannel::PrivateMessage(character("Sled Dog"), character("Arctic Fox")),
+            message,
+        ),
+    };
+    event::handle_event(&harness.app.handle(), &mut batches, update).await;
+    let payload = harness.expect("message").await;
+    assert_eq!(payload["data"]["message"]["content"]["content"], "Hello");
+
+    let update = AccountEvent {
+        account: "husky".to_string(),
+        event: UpdateEvent::Invite(EventInvite {
+            session: character("Sled Dog"),
+            sender: character("Arctic Fox"),
+            channel: channel("ADH-0123"),
+            title: "Den".to_string(),
+        }),
+    };
+    event::handle_event(&harness.app.handle(), &mut batches, update).await;
+    let payload = harness.expect("invite").await;
+    assert_eq!(payload["data"]["title"], "Den");
+
+    let update = AccountEvent {
+        account: "husky".to_string(),
+        event: UpdateEvent::Friends,
+    };
+    event::handle_event(&harness.app.handle(), &mut batches, update).await;
+    assert!(batches.contains_key("husky"));
+}
@@ -0,0 +1,334 @@
+// Credentials live here, not in the webview. The vault is a single encrypted file in the app
+// data dir, holding account passwords and the last API ticket for each.
+//
+// Without a master passphrase, the key is derived from an empty one. That keeps the password out
+// of plain sight (and out of localStorage), but anyone with the file and this source can open it.
+// Setting a passphrase is what actually protects it.
+use std::{collections::HashMap, fmt, fs, path::PathBuf, sync::Mutex};
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use chrono::{Duration, Utc};
+use f_chat_rs::util::Timestamp;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+use thiserror::Error;
+
+use crate::store::{write_atomic, StoreError};
+
+pub const VAULT: &str = "vault.json";
+const VAULT_VERSION: u32 = 1;
+// F-List tickets are good for half an hour. Leave some slack so we don't log in on a dying one.
+const TICKET_LIFETIME: i64 = 25;
+
+#[derive(Error, Debug, Serialize)]
+pub enum VaultError {
+    #[error("Vault is locked")]
+    Locked,
+    #[error("Wrong passphrase")]
+    WrongPassphrase,
+    #[error("No saved credentials for {0}")]
+    NoCredentials(String),
+    #[error("Vault file is damaged or from a newer version")]
+    Corrupt,
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("Login failed: {0}")]
+    Login(String),
+}
+
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    version: u32,
+    // Whether a master passphrase was set, so the UI knows to ask for one.
+    passphrase: bool,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct VaultData {
+    accounts: HashMap<String, Credentials>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Credentials {
+    pub password: String,
+    pub ticket: Option<Ticket>,
+}
+
+// Anything that gets printed shouldn't have the password in it.
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("password", &"<redacted>")
+            .field("ticket", &self.ticket)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ticket {
+    pub ticket: String,
+    pub expires: Timestamp,
+}
+
+impl Ticket {
+    pub fn new(ticket: String) -> Self {
+        Ticket {
+            ticket,
+            expires: Utc::now() + Duration::minutes(TICKET_LIFETIME),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.expires > Utc::now()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct VaultStatus {
+    pub exists: bool,
+    pub unlocked: bool,
+    pub passphrase: bool,
+    // Only known once unlocked.
+    pub accounts: Vec<String>,
+}
+
+struct Unlocked {
+    key: [u8; 32],
+    salt: Vec<u8>,
+    passphrase: bool,
+    data: VaultData,
+}
+
+pub struct Vault {
+    path: PathBuf,
+    unlocked: Mutex<Option<Unlocked>>,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32], VaultError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| VaultError::Corrupt)?;
+    Ok(key)
+}
+
+impl Vault {
+    pub fn new(dir: PathBuf) -> Self {
+        Vault {
+            path: dir.join(VAULT),
+            unlocked: Mutex::new(None),
+        }
+    }
+
+    fn read_file(&self) -> Result<Option<VaultFile>, VaultError> {
+        match fs::read(&self.path) {
+            Ok(raw) => match serde_json::from_slice::<VaultFile>(&raw) {
+                Ok(file) if file.version <= VAULT_VERSION => Ok(Some(file)),
+                _ => Err(VaultError::Corrupt),
+            },
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn status(&self) -> VaultStatus {
+        let file = self.read_file().ok().flatten();
+        let unlocked = self.unlocked.lock().unwrap();
+        VaultStatus {
+            exists: file.is_some(),
+            unlocked: unlocked.is_some(),
+            passphrase: file.map_or(false, |v| v.passphrase),
+            accounts: unlocked
+                .as_ref()
+                .map_or(Vec::new(), |v| v.data.accounts.keys().cloned().collect()),
+        }
+    }
+
+    fn open(file: VaultFile, passphrase: Option<&str>) -> Result<Unlocked, VaultError> {
+        let key = derive_key(passphrase.unwrap_or_default(), &file.salt)?;
+        let plaintext = XChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(XNonce::from_slice(&file.nonce), file.ciphertext.as_ref())
+            .map_err(|_| VaultError::WrongPassphrase)?;
+        Ok(Unlocked {
+            key,
+            salt: file.salt,
+            passphrase: file.passphrase,
+            data: serde_json::from_slice(&plaintext).map_err(|_| VaultError::Corrupt)?,
+        })
+    }
+
+    // A fresh salt every time, so the old key is no use for anything written from here on.
+    fn sealed(passphrase: Option<&str>, data: VaultData) -> Result<Unlocked, VaultError> {
+        let mut salt = vec![0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        Ok(Unlocked {
+            key: derive_key(passphrase.unwrap_or_default(), &salt)?,
+            salt,
+            passphrase: passphrase.map_or(false, |v| !v.is_empty()),
+            data,
+        })
+    }
+
+    // Opens the vault, or makes a new one with this passphrase if there isn't one yet.
+    pub fn unlock(&self, passphrase: Option<&str>) -> Result<(), VaultError> {
+        let unlocked = match self.read_file()? {
+            Some(file) => Vault::open(file, passphrase)?,
+            None => {
+                let unlocked = Vault::sealed(passphrase, Default::default())?;
+                self.write(&unlocked)?;
+                unlocked
+            }
+        };
+        *self.unlocked.lock().unwrap() = Some(unlocked);
+        Ok(())
+    }
+
+    // Sets, changes or (with an empty new one) removes the master passphrase. The old one has to
+    // be right even if the vault's already unlocked; everything is encrypted again under the new.
+    // A vault that isn't there yet is just made with it.
+    pub fn set_passphrase(&self, old: Option<&str>, new: Option<&str>) -> Result<(), VaultError> {
+        let mut unlocked = self.unlocked.lock().unwrap();
+        let data = match self.read_file()? {
+            Some(file) => Vault::open(file, old)?.data,
+            None => Default::default(),
+        };
+        let sealed = Vault::sealed(new, data)?;
+        self.write(&sealed)?;
+        *unlocked = Some(sealed);
+        Ok(())
+    }
+
+    // Vaults without a passphrase can be opened on startup without asking anyone.
+    pub fn unlock_without_passphrase(&self) {
+        if let Ok(Some(file)) = self.read_file() {
+            if !file.passphrase {
+                let _ = self.unlock(None);
+            }
+        }
+    }
+
+    // For remembering a login. A first run has no vault yet, so that's where it gets made;
+    // one that's there but locked needs its passphrase first.
+    pub fn ensure_unlocked(&self) -> Result<(), VaultError> {
+        if self.unlocked.lock().unwrap().is_some() {
+            return Ok(());
+        }
+        match self.read_file()? {
+            Some(_) => Err(VaultError::Locked),
+            None => self.unlock(None),
+        }
+    }
+
+    pub fn lock(&self) {
+        *self.unlocked.lock().unwrap() = None;
+    }
+
+    fn write(&self, unlocked: &Unlocked) -> Result<(), VaultError> {
+        let mut nonce = vec![0u8; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let plaintext = serde_json::to_vec(&unlocked.data).map_err(StoreError::from)?;
+        let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&unlocked.key))
+            .encrypt(XNonce::from_slice(&nonce), plaintext.as_ref())
+            .map_err(|_| VaultError::Corrupt)?;
+        let file = VaultFile {
+            version: VAULT_VERSION,
+            passphrase: unlocked.passphrase,
+            salt: unlocked.salt.clone(),
+            nonce,
+            ciphertext,
+        };
+        write_atomic(
+            &self.path,
+            &serde_json::to_vec(&file).map_err(StoreError::from)?,
+        )?;
+        Ok(())
+    }
+
+    fn modify<T>(&self, f: impl FnOnce(&mut VaultData) -> T) -> Result<T, VaultError> {
+        let mut unlocked = self.unlocked.lock().unwrap();
+        let unlocked = unlocked.as_mut().ok_or(VaultError::Locked)?;
+        let result = f(&mut unlocked.data);
+        self.write(unlocked)?;
+        Ok(result)
+    }
+
+    pub fn credentials(&self, username: &str) -> Result<Credentials, VaultError> {
+        let unlocked = self.unlocked.lock().unwrap();
+        let unlocked = unlocked.as_ref().ok_or(VaultError::Locked)?;
+        unlocked
+            .data
+            .accounts
+            .get(username)
+            .cloned()
+            .ok_or_else(|| VaultError::NoCredentials(username.to_string()))
+    }
+
+    pub fn set_credentials(
+        &self,
+        username: String,
+        password: String,
+        ticket: Option<Ticket>,
+    ) -> Result<(), VaultError> {
+        self.modify(|data| {
+            data.accounts
+                .insert(username, Credentials { password, ticket });
+        })
+    }
+
+    pub fn set_ticket(&self, username: &str, ticket: Ticket) -> Result<(), VaultError> {
+        self.modify(|data| {
+            if let Some(credentials) = data.accounts.get_mut(username) {
+                credentials.ticket = Some(ticket);
+            }
+        })
+    }
+
+    pub fn forget(&self, username: &str) -> Result<bool, VaultError> {
+        self.modify(|data| data.accounts.remove(username).is_some())
+    }
+}
+
+#[tauri::command]
+pub async fn vault_status(vault: State<'_, Vault>) -> Result<VaultStatus, ()> {
+    Ok(vault.status())
+}
+
+#[tauri::command]
+pub async fn unlock_vault(
+    vault: State<'_, Vault>,
+    passphrase: Option<String>,
+) -> Result<VaultStatus, VaultError> {
+    vault.unlock(passphrase.as_deref())?;
+    Ok(vault.status())
+}
+
+#[tauri::command]
+pub async fn set_vault_passphrase(
+    vault: State<'_, Vault>,
+    old: Option<String>,
+    new: Option<String>,
+) -> Result<VaultStatus, VaultError> {
+    vault.set_passphrase(old.as_deref(), new.as_deref())?;
+    Ok(vault.status())
+}
+
+#[tauri::command]
+pub async fn lock_vault(vault: State<'_, Vault>) -> Result<(), ()> {
+    vault.lock();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn forget_credentials(
+    vault: State<'_, Vault>,
+    username: String,
+) -> Result<bool, VaultError> {
+    vault.forget(&username)
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Mutex, RwLock},
+};
+
+use f_chat_rs::{
+    data::{Character, Status},
+    util::Timestamp,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{Cache, PresenceChange, PresenceKind};
+
+pub const WATCH_LIST: &str = "watch_list.json";
+// Like the console in the official client, this is for glancing at, not an archive.
+const ACTIVITY_FEED_LIMIT: usize = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchList {
+    pub friends: bool,
+    pub bookmarks: bool,
+    pub characters: HashSet<Character>,
+}
+
+impl Default for WatchList {
+    fn default() -> Self {
+        WatchList {
+            friends: true,
+            bookmarks: true,
+            characters: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchAlert {
+    pub character: Character,
+    pub kind: PresenceKind,
+    pub status: Status,
+    pub status_message: String,
+    pub at: Timestamp,
+    pub friend: bool,
+    pub bookmark: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Watch {
+    list: RwLock<WatchList>,
+    feed: Mutex<VecDeque<WatchAlert>>,
+}
+
+impl Watch {
+    pub fn new(list: WatchList) -> Self {
+        Watch {
+            list: RwLock::new(list),
+            ..Default::default()
+        }
+    }
+
+    pub fn get_list(&self) -> WatchList {
+        self.list.read().unwrap().clone()
+    }
+
+    pub fn set_list(&self, list: WatchList) {
+        *self.list.write().unwrap() = list;
+    }
+
+    // Filters presence changes down to the watched characters, and records them in the feed.
+    pub fn alerts(&self, cache: &Cache, changes: &[PresenceChange]) -> Vec<WatchAlert> {
+        let list = self.list.read().unwrap();
+        let friends = cache.friend_set();
+        let alerts: Vec<WatchAlert> = changes
+            .iter()
+            .filter_map(|change| {
+                let friend = friends.contains(&change.character);
+                let bookmark = cache.is_bookmark(&change.character);
+                let watched = list.characters.contains(&change.character)
+                    || (list.friends && friend)
+                    || (list.bookmarks && bookmark);
+                watched.then(|| WatchAlert {
+                    character: change.character,
+                    kind: change.kind,
+                    status: change.status,
+                    status_message: change.status_message.clone(),
+                    at: change.at,
+                    friend,
+                    bookmark,
+                })
+            })
+            .collect();
+
+        if !alerts.is_empty() {
+            let mut feed = self.feed.lock().unwrap();
+            feed.extend(alerts.iter().cloned());
+            while feed.len() > ACTIVITY_FEED_LIMIT {
+                feed.pop_front();
+            }
+        }
+        alerts
+    }
+
+    // Newest first.
+    pub fn get_feed(&self, limit: Option<usize>) -> Vec<WatchAlert> {
+        let feed = self.feed.lock().unwrap();
+        feed.iter()
+            .rev()
+            .take(limit.unwrap_or(ACTIVITY_FEED_LIMIT))
+            .cloned()
+            .collect()
+    }
+}
@@ -3,52 +3,67 @@
 // At a future date, the "just enough to display" can be shrunk down massively to save memory.
 // But for now, it's a bit more than we'll ever display.
 
-import { writable } from "svelte/store";
+import { get, writable } from "svelte/store";
 import { listen } from '@tauri-apps/api/event';
 import type { Event } from '@tauri-apps/api/event';
-import { getFriends, getBookmarks, getChannel, getCharacter, getAllCharacters } from "$lib/rust";
-import type { Channel, Character, ChannelData, CharacterData } from "./types";
+import { getFriends, getBookmarks, getAllCharacters } from "$lib/rust";
+import type { Channel, Character, ChannelData, CharacterData, CharacterDelta, ForAccount } from "./types";
 import { browser } from "$app/environment";
+import { username } from "$lib/account";
 
 export const bookmarks = writable<Character[]>([]);
 export const friends = writable<Character[]>([]);
 export const channels = writable<Record<Channel, ChannelData>>({});
 export const characters = writable<Record<Character, CharacterData>>({});
+// The version of the backend's character store that `characters` reflects.
+let characterVersion = 0;
+
+// These stores hold the current account's view. Other accounts' events are left for whoever shows them.
+function listenAccount<T>(event: string, handler: (payload: T) => void) {
+  return listen(event, (e: Event<ForAccount<T>>) => {
+    if (e.payload.account === get(username)) handler(e.payload.data);
+  })
+}
 
 if (browser) {
-  listen('update_bookmark', syncBookmarks)
-  listen('update_friends', syncFriends)
-  listen('update_channel', async (e: Event<Channel>) => {
-    let data = await getChannel(e.payload);
-    channels.update((v) => {
-      v[e.payload] = data;
-      return v;
-    });
+  // Updates are batched on the backend and carry their data, so there's no need to fetch.
+  listenAccount('update_bookmarks', (v: Character[]) => bookmarks.set(v))
+  listenAccount('update_friends', (v: Character[]) => friends.set(v))
+  listenAccount('update_channels', (data: Record<Channel, ChannelData>) => {
+    channels.update((v) => Object.assign(v, data));
   })
-  listen('update_character', async (e: Event<Character>) => {
-    let data = await getCharacter(e.payload);
+  listenAccount('characters_delta', (delta: CharacterDelta) => {
+    if (delta.to <= characterVersion) return; // Already covered by a snapshot
+    if (delta.from > characterVersion) {
+      // Missed something in between, so the delta can't be trusted.
+      syncCharacters();
+      return;
+    }
     characters.update((v) => {
-      v[e.payload] = data;
+      Object.assign(v, delta.added, delta.changed);
+      for (let character of delta.removed) delete v[character];
       return v;
-    })
+    });
+    characterVersion = delta.to;
   })
+  listenAccount('characters_resync', syncCharacters)
   syncBookmarks();
   syncFriends();
   syncCharacters();
 }
 
 export async function syncBookmarks() {
-  bookmarks.set(await getBookmarks());
+  bookmarks.set(await getBookmarks(get(username) ?? undefined));
 }
 
 export async function syncFriends() {
-  friends.set(await getFriends());
+  friends.set(await getFriends(get(username) ?? undefined));
 }
 
 export async function syncCharacters() {
-  let chars = await getAllCharacters();
-  console.log(chars);
-  characters.set(chars);
+  let snapshot = await getAllCharacters(get(username) ?? undefined);
+  characterVersion = snapshot.version;
+  characters.set(snapshot.characters);
 }
 
 // No syncChannels?
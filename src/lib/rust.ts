@@ -1,47 +1,82 @@
 // This is where I'm packing all of the functions for talking to Tauri backend
 import { invoke } from "@tauri-apps/api/tauri";
 import { sessions } from "$lib/session";
-import type { Channel, ChannelData, Character, CharacterData, Message, MessageChannel, MessageTarget } from "$lib/types";
+import type { Channel, ChannelData, Character, CharacterData, MessageChannel, MessageTarget, UnreadData, UnreadEvent, CharacterSnapshot, LastSeen, WatchList, WatchAlert, ChannelKind, ChannelMember, MemberFilter, ChannelChange, Settings, VaultStatus, AccountData, ExportFormat, ExportRange, ImportSummary, MapStats, ChannelLists, MessageRecord, RollStats, LocalRoll, LogEntry, Draft, ScheduledMessage } from "$lib/types";
 
 export type RTCharacter = {
   name: string,
   status: string
 }
 
-export function login(username: string, password: string) {
-  return invoke("login", { username, password });
+// With remember set, the password is kept in the backend's vault for loginSaved.
+export function login(username: string, password: string, remember?: boolean) {
+  return invoke("login", { username, password, remember });
 }
 
-export async function startSession(character: string) {
-  await invoke("start_session", { character: character });
+export function loginSaved(username?: string) {
+  return invoke("login_saved", { username });
+}
+
+export function vaultStatus(): Promise<VaultStatus> {
+  return invoke("vault_status");
+}
+
+export function unlockVault(passphrase?: string): Promise<VaultStatus> {
+  return invoke("unlock_vault", { passphrase });
+}
+
+// The old passphrase is needed even when unlocked. Leaving the new one out (or empty) removes it.
+export function setVaultPassphrase(old?: string, newPassphrase?: string): Promise<VaultStatus> {
+  return invoke("set_vault_passphrase", { old, new: newPassphrase });
+}
+
+export async function lockVault() {
+  await invoke("lock_vault");
+}
+
+export function forgetCredentials(username: string): Promise<boolean> {
+  return invoke("forget_credentials", { username });
+}
+
+// Everyone logged in, oldest first. Functions that take an account default to the latest login.
+export function getAccounts(): Promise<AccountData[]> {
+  return invoke("get_accounts");
+}
+
+export async function startSession(character: string, account?: string) {
+  await invoke("start_session", { character: character, account });
   sessions.update((v) => {
     v.unshift(character);
     return v;
   })
 }
 
-export function getOwnCharacters(): Promise<string[]> {
-  return invoke("get_own_characters", {});
+export function getOwnCharacters(account?: string): Promise<string[]> {
+  return invoke("get_own_characters", { account });
+}
+
+export function getFriends(account?: string): Promise<Character[]> {
+  return invoke("get_friends", { account });
 }
 
-export function getFriends(): Promise<Character[]> {
-  return invoke("get_friends", {});
+export function getBookmarks(account?: string): Promise<Character[]> {
+  return invoke("get_bookmarks", { account });
 }
 
-export function getBookmarks(): Promise<Character[]> {
-  return invoke("get_bookmarks", {});
+export function getCharacter(character: Character, account?: string): Promise<CharacterData> {
+  return invoke("get_character", { character, account });
 }
 
-export function getCharacter(character: Character): Promise<CharacterData> {
-  return invoke("get_character", { character });
+export function getChannel(channel: Channel, account?: string): Promise<ChannelData> {
+  return invoke("get_channel", { channel, account });
 }
 
-export function getChannel(channel: Channel): Promise<ChannelData> {
-  return invoke("get_channel", { channel });
+export function getChannelList(account?: string): Promise<ChannelLists> {
+  return invoke("get_channel_list", { account });
 }
 
-export function getAllCharacters(): Promise<Record<Character, CharacterData>> {
-  return invoke("get_all_characters");
+export function getAllCharacters(account?: string): Promise<CharacterSnapshot> {
+  return invoke("get_all_characters", { account });
 }
 
 export function getSessions(): Promise<Character[]> {
@@ -57,14 +92,179 @@ export async function sendMessage(session: Character, target: MessageTarget, mes
   await invoke("session_send_message", { session, target, message });
 }
 
+// Rejects expressions the server wouldn't take, with the reason, before anything is sent.
 export async function sendDice(session: Character, target: MessageTarget, dice: string) {
   await invoke("session_send_dice", { session, target, dice });
 }
 
+// Same syntax as /roll, plus keep/drop ("4d6kh3", "4d6dl1") and exploding dice ("1d6!").
+// The roll goes in the character's notes: { own_character: character, other_character: character }.
+// Works logged out too; it's only shown as a message event if the character is logged in.
+export function rollLocal(character: Character, expression: string, seed?: number): Promise<LocalRoll> {
+  return invoke("roll_local", { character, expression, seed });
+}
+
+// Notes are kept in the logs rather than the cache, so they're read from there.
+export function getNotes(character: Character, since?: number): Promise<LogEntry[]> {
+  return invoke("get_notes", { character, since });
+}
+
+export function getRollStats(channel: MessageChannel, account?: string, since?: number): Promise<RollStats> {
+  return invoke("get_roll_stats", { channel, account, since });
+}
+
 export async function joinChannel(session: Character, channel: Channel) {
   await invoke("session_join_channel", { session, channel });
 }
 
-export async function getMessages(channel: MessageChannel): Promise<Message[]> {
-  return await invoke("get_messages", { channel });
+// What's come in since login. A character's notes aren't in here; they're read with getNotes.
+export async function getMessages(channel: MessageChannel, account?: string): Promise<MessageRecord[]> {
+  return await invoke("get_messages", { channel, account });
+}
+
+export async function markRead(channel: MessageChannel, upTo?: number, account?: string): Promise<UnreadData> {
+  return await invoke("mark_read", { channel, upTo, account });
+}
+
+export async function getUnreadSummary(): Promise<UnreadEvent[]> {
+  return await invoke("get_unread_summary");
+}
+
+// Entries and approximate bytes for each of the cache's maps, by name.
+export function getCacheStats(account?: string): Promise<Record<string, MapStats>> {
+  return invoke("get_cache_stats", { account });
+}
+
+// Writes the conversation to `path`, returning how many messages went in. Range ends are timestamps.
+export function exportLogs(channel: MessageChannel, format: ExportFormat, path: string, range?: ExportRange, account?: string): Promise<number> {
+  return invoke("export_logs", { channel, format, path, range, account });
+}
+
+// `path` is the official client's (or Horizon's) data directory, or one character's directory in it.
+// Emits "import_progress" as it goes through the files.
+export function importLogs(path: string): Promise<ImportSummary> {
+  return invoke("import_logs", { path });
+}
+
+export function getLastSeen(character: Character, account?: string): Promise<LastSeen | null> {
+  return invoke("get_last_seen", { character, account });
+}
+
+export function getWatchList(): Promise<WatchList> {
+  return invoke("get_watch_list");
+}
+
+export async function setWatchList(watchList: WatchList) {
+  await invoke("set_watch_list", { watchList });
+}
+
+export function getActivityFeed(limit?: number): Promise<WatchAlert[]> {
+  return invoke("get_activity_feed", { limit });
+}
+
+// Channel moderation (all take session, and fail unless it's an op in the channel)
+export async function channelKick(session: Character, channel: Channel, character: Character) {
+  await invoke("channel_kick", { session, channel, character });
+}
+
+export async function channelBan(session: Character, channel: Channel, character: Character) {
+  await invoke("channel_ban", { session, channel, character });
+}
+
+export async function channelUnban(session: Character, channel: Channel, character: Character) {
+  await invoke("channel_unban", { session, channel, character });
+}
+
+export async function channelTimeout(session: Character, channel: Channel, character: Character, minutes: number) {
+  await invoke("channel_timeout", { session, channel, character, minutes });
+}
+
+export async function channelListBans(session: Character, channel: Channel) {
+  await invoke("channel_list_bans", { session, channel });
+}
+
+export async function channelPromoteOp(session: Character, channel: Channel, character: Character) {
+  await invoke("channel_promote_op", { session, channel, character });
+}
+
+export async function channelDemoteOp(session: Character, channel: Channel, character: Character) {
+  await invoke("channel_demote_op", { session, channel, character });
+}
+
+export async function channelSetOwner(session: Character, channel: Channel, character: Character) {
+  await invoke("channel_set_owner", { session, channel, character });
+}
+
+export async function channelSetMode(session: Character, channel: Channel, mode: string) {
+  await invoke("channel_set_mode", { session, channel, mode });
+}
+
+export async function channelSetDescription(session: Character, channel: Channel, description: string) {
+  await invoke("channel_set_description", { session, channel, description });
+}
+
+export async function channelSetTitle(session: Character, channel: Channel, title: string) {
+  await invoke("channel_set_title", { session, channel, title });
+}
+
+// Private rooms
+export async function createPrivateChannel(session: Character, title: string) {
+  await invoke("create_private_channel", { session, title });
+}
+
+export async function setChannelPrivate(session: Character, channel: Channel, isPrivate: boolean) {
+  await invoke("set_channel_private", { session, channel, private: isPrivate });
+}
+
+export async function inviteToChannel(session: Character, channel: Channel, character: Character) {
+  await invoke("invite_to_channel", { session, channel, character });
+}
+
+export async function acceptInvite(session: Character, channel: Channel) {
+  await invoke("accept_invite", { session, channel });
+}
+
+export function getChannelKind(channel: Channel, account?: string): Promise<ChannelKind | null> {
+  return invoke("get_channel_kind", { channel, account });
+}
+
+export function getChannelMembers(channel: Channel, filter?: MemberFilter, account?: string): Promise<ChannelMember[] | null> {
+  return invoke("get_channel_members", { channel, filter, account });
+}
+
+export function getChannelHistory(channel: Channel, account?: string): Promise<ChannelChange[]> {
+  return invoke("get_channel_history", { channel, account });
+}
+
+export function getSettings(): Promise<Settings> {
+  return invoke("get_settings");
+}
+
+// Only the parts being changed need to be passed; they're merged into the current settings.
+export function updateSettings(patch: object): Promise<Settings> {
+  return invoke("update_settings", { patch });
+}
+
+// Drafts are kept per character, so rooms need to say who's writing. PMs already do.
+export function saveDraft(character: Character, channel: MessageChannel, text: string): Promise<Draft> {
+  return invoke("save_draft", { character, channel, text });
+}
+
+export function getDraft(character: Character, channel: MessageChannel): Promise<Draft> {
+  return invoke("get_draft", { character, channel });
+}
+
+// With retry (the default), a message due while the session is offline keeps trying until an hour after `at`.
+// Without it, a message that can't go out when it's due is dropped.
+// scheduled_sent and scheduled_dropped come as ForAccount, like every other event.
+export function scheduleMessage(session: Character, target: MessageTarget, text: string, at: number, retry?: boolean): Promise<ScheduledMessage> {
+  return invoke("schedule_message", { session, target, text, at, retry });
+}
+
+export function cancelScheduled(id: number): Promise<ScheduledMessage> {
+  return invoke("cancel_scheduled", { id });
+}
+
+export function getScheduled(session?: Character): Promise<ScheduledMessage[]> {
+  return invoke("get_scheduled", { session });
 }
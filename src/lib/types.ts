@@ -23,3 +23,213 @@ export type Message = {
   character: Character,
   content: MessageContent
 }
+// What a message was said under (the room's title, the sender's gender), then and now.
+export type MessageContext = {
+  title: string | null,
+  gender: string | null
+}
+export type LogEntry = Message & {
+  sent_as: MessageContext
+}
+export type MessageRecord = Message & {
+  sent_as: MessageContext,
+  current: MessageContext
+}
+export type RollRecord = {
+  at: number,
+  character: Character
+} & (
+  { type: "dice", dice: string[], results: number[], total: number }
+  | { type: "bottle", target: Character }
+)
+export type TermRoll = {
+  term: string,
+  dice: number[],
+  kept: boolean[],
+  result: number
+}
+export type LocalRoll = {
+  seed: number,
+  terms: TermRoll[],
+  total: number
+}
+export type Tally = {
+  rolls: number,
+  mean: number,
+  lowest: number,
+  highest: number
+}
+export type RollStats = {
+  rolls: number,
+  characters: Record<Character, Tally>,
+  dice: Record<string, Tally & { expected: number }>,
+  bottles: Record<Character, number>,
+  history: RollRecord[]
+}
+export type UnreadData = {
+  last_read: number | null,
+  unread: number,
+  mentions: number
+}
+export type UnreadEvent = {
+  account: string,
+  channel: MessageChannel
+} & UnreadData
+export type CharacterSnapshot = {
+  version: number,
+  characters: Record<Character, CharacterData>
+}
+export type CharacterDelta = {
+  from: number,
+  to: number,
+  added: Record<Character, CharacterData>,
+  changed: Record<Character, CharacterData>,
+  removed: Character[]
+}
+export type PresenceKind = "online" | "offline" | "status";
+export type PresenceChange = {
+  character: Character,
+  kind: PresenceKind,
+  status: string,
+  status_message: string,
+  at: number
+}
+export type LastSeen = {
+  online: boolean,
+  last_seen: number
+}
+export type WatchList = {
+  friends: boolean,
+  bookmarks: boolean,
+  characters: Character[]
+}
+export type WatchAlert = PresenceChange & {
+  friend: boolean,
+  bookmark: boolean
+}
+export type ChannelKind = "official" | "public" | "private";
+export type Invite = {
+  session: Character,
+  sender: Character,
+  channel: Channel,
+  title: string
+}
+export type ChannelRole = "member" | "op" | "owner" | "global_op";
+export type ChannelMember = {
+  character: Character,
+  gender: string,
+  status: string,
+  status_message: string,
+  role: ChannelRole,
+  friend: boolean,
+  bookmark: boolean
+}
+export type MemberFilter = {
+  genders?: string[],
+  statuses?: string[]
+}
+export type ChannelChange = {
+  channel: Channel,
+  at: number,
+  by: Character | null
+} & (
+  { field: "title", old: string, new: string }
+  | { field: "description", old: string, new: string }
+  | { field: "mode", old: string, new: string }
+)
+// The server's room lists, by channel, with how many are in each.
+export type ChannelLists = {
+  official: Record<Channel, number>,
+  unofficial: Record<Channel, number>
+}
+// Sent as "channel_list_updated" when a list comes in different to last time.
+export type ChannelListChange = {
+  list: "official" | "unofficial",
+  added: Record<Channel, number>,
+  removed: Channel[],
+  counts: Record<Channel, number>
+}
+// Who came and went from a list, as a resent list or a single add/remove.
+export type ListChange = {
+  added: Character[],
+  removed: Character[]
+} & (
+  { list: "friends" }
+  | { list: "bookmarks" }
+  | { list: "global_ops" }
+  | { list: "channel_ops", channel: Channel }
+)
+export type Settings = {
+  version: number,
+  global: {
+    retain_offline: boolean,
+    last_account: string | null,
+    message_limit: number,
+    message_budget: number, // MiB
+    log_evicted: boolean
+  },
+  accounts: Record<string, { auto_login: boolean }>,
+  characters: Record<Character, { highlight_words: string[] }>
+}
+export type VaultStatus = {
+  exists: boolean,
+  unlocked: boolean,
+  passphrase: boolean,
+  accounts: string[]
+}
+export type AccountData = {
+  account: string,
+  own_characters: Character[],
+  sessions: Character[]
+}
+// Every backend event comes wrapped with the account it's about.
+export type ForAccount<T> = {
+  account: string,
+  data: T
+}
+export type ExportFormat = "text" | "html" | "json";
+export type ExportRange = {
+  from?: number,
+  to?: number
+}
+export type ImportSummary = {
+  files: number,
+  imported: number,
+  skipped: number,
+  failed: number,
+  errors: string[]
+}
+export type ImportProgress = {
+  file: string,
+  done: number,
+  total: number
+}
+export type MapStats = {
+  entries: number,
+  bytes: number
+}
+export type DraftVersion = {
+  text: string,
+  saved: number
+}
+export type Draft = {
+  text: string,
+  saved: number | null,
+  // Newest first
+  history: DraftVersion[]
+}
+export type ScheduledMessage = {
+  id: number,
+  session: Character,
+  target: MessageTarget,
+  text: string,
+  at: number,
+  retry: boolean,
+  attempts: number,
+  next_attempt: number | null,
+  // Going out right now, so it can't be cancelled.
+  sending: boolean
+}
+export type DroppedMessage = ScheduledMessage & {
+  reason: string
+}
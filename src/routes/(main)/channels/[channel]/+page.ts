@@ -0,0 +1,8 @@
+import type { PageLoad } from "./$types";
+
+
+export const load: PageLoad = async ({ params }) => {
+  return {
+    channel: params.channel
+  }
+};